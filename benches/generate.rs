@@ -0,0 +1,49 @@
+//! Times complete generations on the bundled tilesets: `cargo bench`.
+//! Every tileset runs with the same seeds, so that numbers can be compared between versions.
+//! The seeds go through a few passes and the fastest one is reported, single runs being too noisy
+//! to compare.
+
+use std::time::Instant;
+
+use rusting_wave_function::{SimpleTiledModel, WfcError, seed_bytes};
+
+const TILESETS: [&str; 4] = ["Castle", "Circuit", "Knots", "Summer"];
+const SIZES: [usize; 2] = [20, 60];
+const RUNS: u64 = 10;
+const PASSES: usize = 3;
+
+fn main() -> Result<(), WfcError> {
+    println!("{:<10} {:>6} {:>6} {:>14} {:>12} {:>14}", "tileset", "size", "runs", "contradictions", "ms/run", "cells/s");
+
+    for tileset in TILESETS {
+        for size in SIZES {
+            let mut model = SimpleTiledModel::new(&format!("./tilesets/{tileset}.xml"), size)?;
+            let mut contradictions = 0;
+            let mut elapsed = f64::INFINITY;
+
+            for _ in 0..PASSES {
+                contradictions = 0;
+                let start = Instant::now();
+                for seed in 0..RUNS {
+                    match model.run(-1, seed_bytes(seed)) {
+                        Ok(()) => {}
+                        Err(WfcError::Contradiction) => contradictions += 1,
+                        Err(e) => return Err(e),
+                    }
+                }
+                elapsed = elapsed.min(start.elapsed().as_secs_f64());
+            }
+
+            println!(
+                "{:<10} {:>6} {:>6} {:>14} {:>12.2} {:>14.0}",
+                tileset,
+                format!("{size}x{size}"),
+                RUNS,
+                contradictions,
+                elapsed * 1000.0 / RUNS as f64,
+                (size * size) as f64 * RUNS as f64 / elapsed,
+            );
+        }
+    }
+    Ok(())
+}
@@ -1,24 +1,32 @@
-pub fn rotate(array: &[u32]) -> Vec<u32> {
-    let total_len = array.len();
+use crate::error::WfcError;
 
-    let size = (total_len as f64).sqrt() as usize;
+pub fn rotate(array: &[u32]) -> Result<Vec<u32>, WfcError> {
+    let size = square_size(array)?;
+    
+    Ok(tile(|x, y| array[size - 1 - y + x * size], size))
+}
 
-    assert_eq!(size * size, total_len, "L'array non rappresenta una griglia quadrata");
+pub fn reflect(array: &[u32]) -> Result<Vec<u32>, WfcError> {
+    let size = square_size(array)?;
     
-    tile(|x, y| array[size - 1 - y + x * size], size)
+    Ok(tile(|x, y| array[size - 1 - x + y * size], size))
 }
 
-pub fn reflect(array: &[u32]) -> Vec<u32> {
+/// Side of the square grid represented by `array`
+fn square_size(array: &[u32]) -> Result<usize, WfcError> {
     let total_len = array.len();
     let size = (total_len as f64).sqrt() as usize;
-    
-    assert_eq!(size * size, total_len, "L'array non rappresenta una griglia quadrata");
-    
-    tile(|x, y| array[size - 1 - x + y * size], size)
+
+    // L'array deve rappresentare una griglia quadrata
+    if size * size != total_len {
+        return Err(WfcError::NotSquare(total_len));
+    }
+    Ok(size)
 }
 
 
-fn tile<F>(f: F, size: usize) -> Vec<u32> 
+/// Builds a `size` x `size` square array, calling `f(x, y)` for each element
+pub fn tile<F>(f: F, size: usize) -> Vec<u32> 
 where 
     F: Fn(usize, usize) -> u32
 {
@@ -36,8 +44,8 @@ pub fn weighted_random(weights: &[f32], r: f32) -> usize {
     let threshold = r * total;
     
     let mut partial_sum = 0.0;
-    for i in 0..weights.len() {
-        partial_sum += weights[i];
+    for (i, weight) in weights.iter().enumerate() {
+        partial_sum += weight;
         if partial_sum >= threshold {
             return i;
         }
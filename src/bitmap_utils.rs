@@ -1,9 +1,11 @@
 use image::{GenericImageView, ImageBuffer, Rgba};
 use std::path::Path;
 
-pub fn load_bitmap<P: AsRef<Path>>(filename: P) -> (Vec<u32>, u32, u32) {
+use crate::error::WfcError;
+
+pub fn load_bitmap<P: AsRef<Path>>(filename: P) -> Result<(Vec<u32>, u32, u32), WfcError> {
     // Loading the image using the image crate
-    let img = image::open(filename).expect("Failed to open image");
+    let img = image::open(filename)?;
     // Inferring dimensions
     let (width, height) = img.dimensions();
     // Converts the image to the RGBA format
@@ -23,10 +25,10 @@ pub fn load_bitmap<P: AsRef<Path>>(filename: P) -> (Vec<u32>, u32, u32) {
         pixels.push(packed)
     }
 
-    (pixels, width, height)
+    Ok((pixels, width, height))
 }
 
-pub fn save_bitmap<P: AsRef<Path>>(filename: P, pixels: &[u32], width: u32, height: u32){
+pub fn save_bitmap<P: AsRef<Path>>(filename: P, pixels: &[u32], width: u32, height: u32) -> Result<(), WfcError>{
     let mut img_buffer = ImageBuffer::new(width, height);
 
     for y in 0..height{
@@ -43,5 +45,64 @@ pub fn save_bitmap<P: AsRef<Path>>(filename: P, pixels: &[u32], width: u32, heig
         }
     }
 
-    img_buffer.save(filename).expect("Failed to save image");
-}
\ No newline at end of file
+    img_buffer.save(filename)?;
+    Ok(())
+}
+
+/// Loads a black and white image as one flag per pixel: `true` where the pixel is opaque
+/// and closer to white than to black
+pub fn load_mask<P: AsRef<Path>>(filename: P) -> Result<(Vec<bool>, u32, u32), WfcError> {
+    let (pixels, width, height) = load_bitmap(filename)?;
+    let mask = pixels.iter()
+        .map(|&pixel| {
+            let (r, g, b, a) = (pixel & 0xFF, (pixel >> 8) & 0xFF, (pixel >> 16) & 0xFF, pixel >> 24);
+            a >= 128 && r + g + b >= 3 * 128
+        })
+        .collect();
+
+    Ok((mask, width, height))
+}
+
+/// Parses a `#rrggbb` color (the `#` is optional) into an opaque packed RGBA color, as returned by [`load_bitmap`]
+pub fn parse_color(value: &str) -> Option<u32> {
+    let hex = value.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let rgb = u32::from_str_radix(hex, 16).ok()?;
+    let (r, g, b) = (rgb >> 16, (rgb >> 8) & 0xFF, rgb & 0xFF);
+    Some((0xFF << 24) | (b << 16) | (g << 8) | r)
+}
+
+/// Loads an image as the luminance of each pixel, from 0 for black to 1 for white
+pub fn load_luminance<P: AsRef<Path>>(filename: P) -> Result<(Vec<f32>, u32, u32), WfcError> {
+    let (pixels, width, height) = load_bitmap(filename)?;
+    let luminance = pixels.iter()
+        .map(|&pixel| {
+            let (r, g, b) = ((pixel & 0xFF) as f32, ((pixel >> 8) & 0xFF) as f32, ((pixel >> 16) & 0xFF) as f32);
+            (0.299 * r + 0.587 * g + 0.114 * b) / 255.0
+        })
+        .collect();
+
+    Ok((luminance, width, height))
+}
+
+/// Weighted average of packed RGBA colors, channel by channel. Transparent black if there are no colors
+pub fn blend<I: IntoIterator<Item = (u32, f32)>>(colors: I) -> u32 {
+    let mut channels = [0f32; 4];
+    let mut total = 0f32;
+
+    for (pixel, weight) in colors {
+        for (c, channel) in channels.iter_mut().enumerate() {
+            *channel += ((pixel >> (8 * c)) & 0xFF) as f32 * weight;
+        }
+        total += weight;
+    }
+
+    if total <= 0.0 {
+        return 0;
+    }
+    channels.iter().enumerate().fold(0, |packed, (c, channel)| {
+        packed | (((channel / total).round() as u32).min(255) << (8 * c))
+    })
+}
@@ -0,0 +1,321 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::{bitmap_utils, error::WfcError, model::{Generator, Model, derive_seed}, simple_tiled::SimpleTiledModel, tile_map::TileMap};
+
+/// A generated chunk of the world
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    /// Tile index of every cell, in row-major order
+    pub tiles: Vec<usize>,
+    /// Seed the chunk was generated with
+    pub seed: u64,
+    /// Border cells holding a tile that can't sit next to the facing cell of a neighbor chunk
+    pub seams: usize,
+}
+
+/// Generates an unbounded world out of `chunk_size` x `chunk_size` chunks of a tileset, addressed by
+/// their coordinates (`cx`, `cy`), with `cy` growing downwards like the rows of the grid.
+///
+/// Each chunk is generated with a seed derived from the world seed and its coordinates. The chunks
+/// are laid out like a checkerboard: the ones where `cx + cy` is even are generated on their own, the
+/// others fill the gap between their four neighbors, which are generated first when needed. The cells
+/// along the borders of a chunk of the second kind are restricted to the tiles that can sit next to
+/// the facing cells; a corner cell gets the tiles allowed by both of its neighbors, and is left free
+/// when there's none. If the chunk still can't be generated, the border cells around its corners,
+/// where the constraints of two neighbors meet, are freed in wider and wider stretches, leaving
+/// seams where the tiles don't match.
+///
+/// A chunk only depends on the world seed and its coordinates: the same chunks are generated
+/// whatever the order they are asked for.
+pub struct ChunkGenerator {
+    model: SimpleTiledModel,
+    chunk_size: usize,
+    world_seed: u64,
+    retries: usize,
+    chunks: HashMap<(i64, i64), Chunk>,
+}
+
+impl ChunkGenerator {
+
+    /// Loads the tileset described by `xml_path`, optionally restricted to the `<subset>` named `subset_name`
+    pub fn new<P: AsRef<Path>>(xml_path: &P, subset_name: Option<&str>, chunk_size: usize, world_seed: u64) -> Result<Self, WfcError> {
+        if chunk_size == 0 {
+            return Err(WfcError::InvalidParameter("Chunks must be at least 1 tile wide".to_string()));
+        }
+
+        Ok(ChunkGenerator {
+            model: SimpleTiledModel::with_subset(xml_path, subset_name, chunk_size, chunk_size)?,
+            chunk_size,
+            world_seed,
+            retries: 0,
+            chunks: HashMap::new(),
+        })
+    }
+
+    /// Number of attempts with derived seeds made before relaxing the constraints of a chunk
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    /// Enables backtracking in the generation of every chunk, see [`Model::set_backtracking`](crate::Model::set_backtracking)
+    pub fn set_backtracking(&mut self, budget: Option<usize>) {
+        self.model.model_mut().set_backtracking(budget);
+    }
+
+    /// Seed of the chunk at (`cx`, `cy`), derived from the world seed
+    pub fn chunk_seed(&self, cx: i64, cy: i64) -> u64 {
+        derive_seed(derive_seed(self.world_seed, cx as u64), cy as u64)
+    }
+
+    /// Generates the chunk at (`cx`, `cy`), unless it already exists, and returns it.
+    /// Generates the neighbors it depends on too, see [`ChunkGenerator`]
+    pub fn generate(&mut self, cx: i64, cy: i64) -> Result<&Chunk, WfcError> {
+        if !self.chunks.contains_key(&(cx, cy)) {
+            let chunk = if (cx + cy).rem_euclid(2) == 0 {
+                self.generate_free(cx, cy)?
+            } else {
+                for (dx, dy, _) in Self::NEIGHBORS {
+                    self.generate(cx + dx, cy + dy)?;
+                }
+                self.generate_between(cx, cy)?
+            };
+            self.chunks.insert((cx, cy), chunk);
+        }
+        Ok(&self.chunks[&(cx, cy)])
+    }
+
+    /// Generates every chunk of the `columns` x `rows` block starting at (`cx`, `cy`), row by row
+    pub fn generate_area(&mut self, cx: i64, cy: i64, columns: usize, rows: usize) -> Result<(), WfcError> {
+        for y in 0..rows as i64 {
+            for x in 0..columns as i64 {
+                self.generate(cx + x, cy + y)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the `columns` x `rows` block of chunks starting at (`cx`, `cy`) as an image at `path`.
+    /// Chunks that haven't been generated are transparent
+    pub fn save(&self, path: &str, cx: i64, cy: i64, columns: usize, rows: usize) -> Result<(), WfcError> {
+        let tilesize = self.model.tile_size() as usize;
+        let width = columns * self.chunk_size * tilesize;
+        let height = rows * self.chunk_size * tilesize;
+        let mut bitmap = vec![0u32; width * height];
+
+        for row in 0..rows {
+            for column in 0..columns {
+                let Some(chunk) = self.chunk(cx + column as i64, cy + row as i64) else {
+                    continue;
+                };
+                for (i, &t) in chunk.tiles.iter().enumerate() {
+                    let x = (column * self.chunk_size + i % self.chunk_size) * tilesize;
+                    let y = (row * self.chunk_size + i / self.chunk_size) * tilesize;
+                    for (dy, tile_row) in self.model.tiles()[t].chunks(tilesize).enumerate() {
+                        let start = x + (y + dy) * width;
+                        bitmap[start..start + tilesize].copy_from_slice(tile_row);
+                    }
+                }
+            }
+        }
+
+        bitmap_utils::save_bitmap(path, &bitmap, width as u32, height as u32)
+    }
+
+    /// Tile names of the `columns` x `rows` block of chunks starting at (`cx`, `cy`).
+    /// Chunks that haven't been generated are unobserved
+    pub fn tile_map(&self, cx: i64, cy: i64, columns: usize, rows: usize) -> TileMap {
+        let width = columns * self.chunk_size;
+        let height = rows * self.chunk_size;
+        let cells = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                let chunk = self.chunk(cx + (x / self.chunk_size) as i64, cy + (y / self.chunk_size) as i64)?;
+                let t = chunk.tiles[x % self.chunk_size + (y % self.chunk_size) * self.chunk_size];
+                Some(self.model.tile_names()[t].clone())
+            })
+            .collect();
+
+        TileMap { width, height, cells }
+    }
+
+    /* Accessors */
+
+    /// Side of a chunk, in tiles
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// The chunk at (`cx`, `cy`), if it has been generated
+    pub fn chunk(&self, cx: i64, cy: i64) -> Option<&Chunk> {
+        self.chunks.get(&(cx, cy))
+    }
+
+    /// Names of the tiles in the form `"<name> <variant>"`, indexed like [`Chunk::tiles`]
+    pub fn tile_names(&self) -> &[String] {
+        self.model.tile_names()
+    }
+
+    /* Helper Functions */
+
+    /// Generates a chunk without constraints
+    fn generate_free(&mut self, cx: i64, cy: i64) -> Result<Chunk, WfcError> {
+        let seed = self.chunk_seed(cx, cy);
+        let model = self.model.model_mut();
+        model.clear_presets();
+        let (_, seed) = model.run_with_retries(-1, seed, self.retries)?;
+
+        Ok(Chunk { tiles: model.observed().iter().flatten().copied().collect(), seed, seams: 0 })
+    }
+
+    /// Generates a chunk fitting between its four neighbors, which have to exist already
+    fn generate_between(&mut self, cx: i64, cy: i64) -> Result<Chunk, WfcError> {
+        let seed = self.chunk_seed(cx, cy);
+        let size = self.chunk_size;
+
+        let borders: Vec<Border> = Self::NEIGHBORS
+            .iter()
+            .map(|&(dx, dy, direction)| Border {
+                neighbor: &self.chunks[&(cx + dx, cy + dy)],
+                direction,
+                facing: Self::facing(size, dx, dy),
+            })
+            .collect();
+        let allowed = Self::border_presets(self.model.model(), &borders);
+
+        // Every border first, then without the border cells closer than `free` to a corner
+        let mut free = 0;
+        loop {
+            let model = self.model.model_mut();
+            model.clear_presets();
+            for (&i, tiles) in &allowed {
+                let (x, y) = (i % size, i / size);
+                let to_corner = x.min(size - 1 - x).max(y.min(size - 1 - y));
+                if to_corner >= free {
+                    model.preset(x, y, tiles)?;
+                }
+            }
+
+            match model.run_with_retries(-1, seed, self.retries) {
+                Ok((_, attempt_seed)) => {
+                    let tiles: Vec<usize> = model.observed().iter().flatten().copied().collect();
+                    let seams = Self::count_seams(model, &borders, &tiles);
+                    return Ok(Chunk { tiles, seed: attempt_seed, seams });
+                }
+                Err(WfcError::Contradiction) if free < size.div_ceil(2) => free += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Tiles allowed in each border cell by `borders`. Corner cells get the tiles allowed by both of
+    /// their neighbors, and no constraint when there's none
+    fn border_presets(model: &Model, borders: &[Border]) -> HashMap<usize, Vec<usize>> {
+        let mut allowed: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut conflicts = Vec::new();
+
+        for border in borders {
+            for &(from, to) in &border.facing {
+                let tiles = model.neighbors(border.neighbor.tiles[from], border.direction);
+                match allowed.get_mut(&to) {
+                    Some(current) => current.retain(|t| tiles.contains(t)),
+                    None => {
+                        allowed.insert(to, tiles.to_vec());
+                    }
+                }
+                if allowed[&to].is_empty() {
+                    conflicts.push(to);
+                }
+            }
+        }
+
+        for i in conflicts {
+            allowed.remove(&i);
+        }
+        allowed
+    }
+
+    /// Number of cells of `tiles` that can't sit next to the facing cell of one of `borders`
+    fn count_seams(model: &Model, borders: &[Border], tiles: &[usize]) -> usize {
+        let mismatched: HashSet<usize> = borders
+            .iter()
+            .flat_map(|border| border.facing.iter().map(move |&(from, to)| (border, from, to)))
+            .filter(|&(border, from, to)| !model.neighbors(border.neighbor.tiles[from], border.direction).contains(&tiles[to]))
+            .map(|(_, _, to)| to)
+            .collect();
+        mismatched.len()
+    }
+
+    /// Pairs of (cell of the neighbor at (`dx`, `dy`), cell of the chunk) facing each other
+    fn facing(size: usize, dx: i64, dy: i64) -> Vec<(usize, usize)> {
+        (0..size)
+            .map(|k| match (dx, dy) {
+                (-1, _) => (size - 1 + k * size, k * size),
+                (1, _) => (k * size, size - 1 + k * size),
+                (_, -1) => (k + (size - 1) * size, k),
+                _ => (k, k + (size - 1) * size),
+            })
+            .collect()
+    }
+
+    /// Offsets of the four neighbors of a chunk, with the direction from them to the chunk
+    const NEIGHBORS: [(i64, i64, usize); 4] = [(-1, 0, 2), (0, -1, 1), (1, 0, 0), (0, 1, 3)];
+}
+
+/// Side of a new chunk touching one of its neighbors
+struct Border<'a> {
+    neighbor: &'a Chunk,
+    /// Direction from the cells of the neighbor to the cells of the new chunk
+    direction: usize,
+    /// Pairs of (cell of the neighbor, cell of the new chunk) facing each other
+    facing: Vec<(usize, usize)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn world() -> ChunkGenerator {
+        let mut world = ChunkGenerator::new(&"tilesets/Castle.xml", None, 6, 42).unwrap();
+        world.set_backtracking(Some(100));
+        world
+    }
+
+    #[test]
+    fn chunks_dont_depend_on_the_generation_order() {
+        let coordinates: Vec<(i64, i64)> = (-1..=1).flat_map(|cy| (-1..=1).map(move |cx| (cx, cy))).collect();
+        let (mut forward, mut backward) = (world(), world());
+        for &(cx, cy) in &coordinates {
+            forward.generate(cx, cy).unwrap();
+        }
+        for &(cx, cy) in coordinates.iter().rev() {
+            backward.generate(cx, cy).unwrap();
+        }
+
+        for &(cx, cy) in &coordinates {
+            let (a, b) = (forward.chunk(cx, cy).unwrap(), backward.chunk(cx, cy).unwrap());
+            assert_eq!((&a.tiles, a.seed, a.seams), (&b.tiles, b.seed, b.seams), "chunk ({cx}, {cy})");
+        }
+    }
+
+    #[test]
+    fn seams_count_the_border_cells_that_dont_match() {
+        let mut world = world();
+        world.generate_area(0, 0, 3, 3).unwrap();
+        let model = world.model.model();
+
+        for (cx, cy) in [(1, 0), (0, 1), (2, 1), (1, 2)] {
+            let chunk = world.chunk(cx, cy).unwrap();
+            let mut mismatched = HashSet::new();
+            for (dx, dy, direction) in ChunkGenerator::NEIGHBORS {
+                let neighbor = world.chunk(cx + dx, cy + dy).unwrap();
+                for (from, to) in ChunkGenerator::facing(world.chunk_size, dx, dy) {
+                    if !model.neighbors(neighbor.tiles[from], direction).contains(&chunk.tiles[to]) {
+                        mismatched.insert(to);
+                    }
+                }
+            }
+            assert_eq!(chunk.seams, mismatched.len(), "chunk ({cx}, {cy})");
+        }
+    }
+}
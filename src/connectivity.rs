@@ -0,0 +1,380 @@
+use std::mem;
+
+use crate::{constraint::{Constraint, Wave}, model::Model};
+
+const UNVISITED: usize = usize::MAX;
+
+/// Keeps the cells of a channel, such as a road or a river network, connected.
+///
+/// Each tile declares the sides through which it carries the channel, as a bitmask where bit `d`
+/// stands for direction `d` (0 left, 1 below, 2 right, 3 above); tiles without any side aren't part
+/// of it. Two neighboring cells are linked when both carry the channel on the side they share.
+///
+/// Either every cell of the channel has to end up in a single network, or only the given points have
+/// to be linked to each other, leaving the rest of the channel free. Whenever the cells that may
+/// still carry the channel change, the constraint fails if the cells that must carry it can no longer
+/// be linked, restricts to the channel the cells that every link between them goes through, and, for a
+/// single network, bans the channel from the cells that can't reach it anymore.
+pub struct ConnectivityConstraint {
+    edges: Vec<u8>,
+    points: Option<Vec<(usize, usize)>>,
+
+    width: usize,
+    height: usize,
+    periodic: (bool, bool),
+    /// Tiles allowed in each cell, those carrying the channel and those carrying it on each side,
+    /// as far as the reported bans go
+    allowed: Vec<usize>,
+    channel: Vec<usize>,
+    open: Vec<[usize; 4]>,
+    required: Vec<bool>,
+    /// Number of cells that must carry the channel
+    musts: usize,
+
+    /// Buffers of the search, kept between checks
+    discovery: Vec<usize>,
+    low: Vec<usize>,
+    below: Vec<usize>,
+}
+
+impl ConnectivityConstraint {
+
+    /// Every cell carrying the channel belongs to one network. `edges` holds the sides of each tile
+    pub fn new(edges: Vec<u8>) -> Self {
+        Self::with_points(edges, None)
+    }
+
+    /// The cells at `points` carry the channel and are linked to each other
+    pub fn between(edges: Vec<u8>, points: Vec<(usize, usize)>) -> Self {
+        Self::with_points(edges, Some(points))
+    }
+
+    fn with_points(edges: Vec<u8>, points: Option<Vec<(usize, usize)>>) -> Self {
+        ConnectivityConstraint {
+            edges,
+            points,
+            width: 0,
+            height: 0,
+            periodic: (false, false),
+            allowed: Vec::new(),
+            channel: Vec::new(),
+            open: Vec::new(),
+            required: Vec::new(),
+            musts: 0,
+            discovery: Vec::new(),
+            low: Vec::new(),
+            below: Vec::new(),
+        }
+    }
+
+    fn carries(&self, t: usize) -> bool {
+        self.edges.get(t).is_some_and(|&edges| edges != 0)
+    }
+
+    /// Whether cell `i` may still carry the channel
+    fn may(&self, i: usize) -> bool {
+        self.channel[i] > 0
+    }
+
+    /// Whether cell `i` has to be linked to the others
+    fn must(&self, i: usize) -> bool {
+        match self.points {
+            Some(_) => self.required[i],
+            None => self.channel[i] > 0 && self.channel[i] == self.allowed[i],
+        }
+    }
+
+    /// Cell next to `i` in direction `d`, if the channel may go from one to the other
+    fn link(&self, i: usize, d: usize) -> Option<usize> {
+        let j = self.neighbor(i, d)?;
+        (self.may(i) && self.may(j) && self.open[i][d] > 0 && self.open[j][(d + 2) % 4] > 0).then_some(j)
+    }
+
+    /// Cell next to `i` in direction `d`, if it's in the grid
+    fn neighbor(&self, i: usize, d: usize) -> Option<usize> {
+        let (x, y) = ((i % self.width) as isize + Model::DX[d], (i / self.width) as isize + Model::DY[d]);
+        let wrap = |coordinate: isize, size: usize, periodic: bool| match coordinate {
+            c if periodic => Some(c.rem_euclid(size as isize) as usize),
+            c if c < 0 || c >= size as isize => None,
+            c => Some(c as usize),
+        };
+        Some(wrap(x, self.width, self.periodic.0)? + wrap(y, self.height, self.periodic.1)? * self.width)
+    }
+
+    /// Updates the counters of cell `i` for tile `t` being banned or given back, returns whether
+    /// the cell changed in a way that matters to the connectivity
+    fn count(&mut self, i: usize, t: usize, banned: bool) -> bool {
+        let step = |count: &mut usize| if banned { *count -= 1 } else { *count += 1 };
+        let (may, must) = (self.may(i), self.must(i));
+        let mut closed = false;
+
+        step(&mut self.allowed[i]);
+        if self.carries(t) {
+            step(&mut self.channel[i]);
+            for d in (0..4).filter(|d| self.edges[t] >> d & 1 == 1) {
+                step(&mut self.open[i][d]);
+                // Only matters if the neighbor was still open towards this cell
+                closed |= self.open[i][d] == 0 && self.neighbor(i, d).is_some_and(|j| self.may(j) && self.open[j][(d + 2) % 4] > 0);
+            }
+        }
+
+        match (must, self.must(i)) {
+            (false, true) => self.musts += 1,
+            (true, false) => self.musts -= 1,
+            _ => {}
+        }
+        closed || may != self.may(i) || must != self.must(i)
+    }
+
+    /// Looks for the cells that must carry the channel from the first one, through the cells that may,
+    /// and bans what would keep them apart
+    fn check(&mut self, wave: &mut Wave) -> bool {
+        if self.musts == 0 {
+            return true;
+        }
+        let cells = self.allowed.len();
+        let Some(root) = (0..cells).find(|&i| self.must(i)) else {
+            return true;
+        };
+        let total = self.musts;
+
+        // Depth first search keeping, for every cell, its discovery time, the earliest cell its
+        // subtree links back to and how many cells that must carry the channel it holds
+        let (mut discovery, mut low, mut below) = (mem::take(&mut self.discovery), mem::take(&mut self.low), mem::take(&mut self.below));
+        discovery.fill(UNVISITED);
+        let mut critical = Vec::new();
+
+        discovery[root] = 0;
+        below[root] = 1;
+        let mut time = 1;
+        let mut stack = vec![(root, 0)];
+        while let Some(top) = stack.last_mut() {
+            let (v, d) = *top;
+            if d < 4 {
+                top.1 += 1;
+                let Some(w) = self.link(v, d) else {
+                    continue;
+                };
+                if discovery[w] == UNVISITED {
+                    discovery[w] = time;
+                    low[w] = time;
+                    below[w] = self.must(w) as usize;
+                    time += 1;
+                    stack.push((w, 0));
+                } else {
+                    low[v] = low[v].min(discovery[w]);
+                }
+                continue;
+            }
+
+            stack.pop();
+            if let Some(&(parent, _)) = stack.last() {
+                low[parent] = low[parent].min(low[v]);
+                below[parent] += below[v];
+                // Every link between the subtree of v and the rest goes through parent
+                if low[v] >= discovery[parent] && below[v] > 0 && below[v] < total && !self.must(parent) {
+                    critical.push(parent);
+                }
+            }
+        }
+
+        // The cells that must carry the channel are all in the tree of the first one
+        let connected = below[root] == total;
+        if connected {
+            for &i in &critical {
+                for t in (0..wave.tile_count()).filter(|&t| !self.carries(t)) {
+                    wave.ban(i, t);
+                }
+            }
+            if self.points.is_none() {
+                for i in (0..cells).filter(|&i| discovery[i] == UNVISITED && self.may(i)) {
+                    for t in (0..wave.tile_count()).filter(|&t| self.carries(t)) {
+                        wave.ban(i, t);
+                    }
+                }
+            }
+        }
+
+        (self.discovery, self.low, self.below) = (discovery, low, below);
+        connected
+    }
+}
+
+impl Constraint for ConnectivityConstraint {
+    fn init(&mut self, wave: &mut Wave) -> bool {
+        let (cells, tiles) = (wave.width() * wave.height(), wave.tile_count());
+        self.width = wave.width();
+        self.height = wave.height();
+        self.periodic = wave.periodic();
+
+        // Inactive cells never carry the channel
+        let channel = (0..tiles).filter(|&t| self.carries(t)).count();
+        let mut open = [0; 4];
+        for (d, count) in open.iter_mut().enumerate() {
+            *count = (0..tiles).filter(|&t| self.edges.get(t).is_some_and(|&edges| edges >> d & 1 == 1)).count();
+        }
+        self.allowed = vec![tiles; cells];
+        self.channel = (0..cells).map(|i| if wave.is_active(i) { channel } else { 0 }).collect();
+        self.open = (0..cells).map(|i| if wave.is_active(i) { open } else { [0; 4] }).collect();
+
+        self.musts = 0;
+        self.discovery = vec![UNVISITED; cells];
+        self.low = vec![0; cells];
+        self.below = vec![0; cells];
+
+        self.required = vec![false; cells];
+        for &(x, y) in self.points.iter().flatten() {
+            if x >= self.width || y >= self.height || !wave.is_active(x + y * self.width) {
+                return false;
+            }
+            if !self.required[x + y * self.width] {
+                self.musts += 1;
+            }
+            self.required[x + y * self.width] = true;
+            for t in (0..tiles).filter(|&t| !self.carries(t)) {
+                wave.ban(x + y * self.width, t);
+            }
+        }
+        true
+    }
+
+    fn on_ban(&mut self, wave: &mut Wave, i: usize, t: usize) -> bool {
+        if self.count(i, t, true) {
+            self.check(wave)
+        } else {
+            true
+        }
+    }
+
+    fn on_unban(&mut self, _wave: &Wave, i: usize, t: usize) {
+        self.count(i, t, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+    use super::*;
+    use crate::model::seed_bytes;
+
+    /// Sides of the tiles: nothing, a horizontal road, a vertical road and the four turns
+    const EDGES: [u8; 7] = [0b0000, 0b0101, 0b1010, 0b0011, 0b0110, 0b1100, 0b1001];
+
+    /// A `width` x `height` wave of the tiles of [`EDGES`], where roads only meet roads
+    fn model(width: usize, height: usize) -> Model {
+        let side = |t: usize, d: usize| EDGES[t] >> d & 1;
+        let tiles = 0..EDGES.len();
+        let propagator = (0..4)
+            .map(|d| tiles.clone().map(|t| tiles.clone().filter(|&u| side(t, d) == side(u, (d + 2) % 4)).collect()).collect())
+            .collect();
+        let mut weights = vec![1.0; EDGES.len()];
+        weights[0] = 3.0;
+        let mut model = Model::new(width, height, 1, weights, propagator).unwrap();
+        model.set_backtracking(Some(1000));
+        model
+    }
+
+    /// Number of road networks in the output of `model`, and the network of each cell
+    fn networks(model: &Model) -> (usize, Vec<Option<usize>>) {
+        let (width, height) = (model.width(), model.height());
+        let tile = |i: usize| model.observed()[i].unwrap();
+        let mut network = vec![None; width * height];
+        let mut count = 0;
+
+        for start in (0..width * height).filter(|&i| EDGES[tile(i)] != 0) {
+            if network[start].is_some() {
+                continue;
+            }
+            network[start] = Some(count);
+            let mut queue = VecDeque::from([start]);
+            while let Some(i) = queue.pop_front() {
+                for d in (0..4).filter(|&d| EDGES[tile(i)] >> d & 1 == 1) {
+                    let (x, y) = ((i % width) as isize + Model::DX[d], (i / width) as isize + Model::DY[d]);
+                    if x < 0 || y < 0 || x >= width as isize || y >= height as isize {
+                        continue;
+                    }
+                    let j = x as usize + y as usize * width;
+                    if network[j].is_none() {
+                        network[j] = Some(count);
+                        queue.push_back(j);
+                    }
+                }
+            }
+            count += 1;
+        }
+        (count, network)
+    }
+
+    #[test]
+    fn the_channel_forms_a_single_network() {
+        let split = (0..10).filter(|&seed| {
+            let mut model = model(8, 8);
+            model.run(-1, seed_bytes(seed)).is_ok() && networks(&model).0 > 1
+        });
+        assert!(split.count() > 0, "the roads should be split without the constraint");
+
+        for seed in 0..10 {
+            let mut model = model(8, 8);
+            model.add_constraint(ConnectivityConstraint::new(EDGES.to_vec()));
+            model.run_with_retries(-1, seed, 10).unwrap();
+            assert_eq!(networks(&model).0, 1, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn points_are_linked() {
+        for seed in 0..10 {
+            let mut model = model(8, 8);
+            model.add_constraint(ConnectivityConstraint::between(EDGES.to_vec(), vec![(0, 1), (7, 6), (3, 7)]));
+            model.run_with_retries(-1, seed, 10).unwrap();
+
+            let (_, network) = networks(&model);
+            let linked = [8, 6 * 8 + 7, 7 * 8 + 3].map(|i| network[i]);
+            assert!(linked[0].is_some() && linked.iter().all(|&n| n == linked[0]), "seed {seed}");
+        }
+    }
+
+    /// Lets the test look into the constraint while the model runs it
+    struct Shared(Rc<RefCell<ConnectivityConstraint>>);
+
+    impl Constraint for Shared {
+        fn init(&mut self, wave: &mut Wave) -> bool {
+            self.0.borrow_mut().init(wave)
+        }
+
+        fn on_ban(&mut self, wave: &mut Wave, i: usize, t: usize) -> bool {
+            self.0.borrow_mut().on_ban(wave, i, t)
+        }
+
+        fn on_unban(&mut self, wave: &Wave, i: usize, t: usize) {
+            self.0.borrow_mut().on_unban(wave, i, t)
+        }
+    }
+
+    #[test]
+    fn undo_restores_the_counters() {
+        let constraint = Rc::new(RefCell::new(ConnectivityConstraint::between(EDGES.to_vec(), vec![(0, 0), (4, 4)])));
+        let mut model = model(5, 5);
+        model.add_constraint(Shared(constraint.clone()));
+        let counters = |c: &ConnectivityConstraint| (c.allowed.clone(), c.channel.clone(), c.open.clone(), c.required.clone(), c.musts);
+        let consistent = |model: &Model, c: &ConnectivityConstraint| (0..25).all(|i| {
+            let allowed: Vec<usize> = (0..EDGES.len()).filter(|&t| model.is_allowed(i, t)).collect();
+            c.allowed[i] == allowed.len() && c.channel[i] == allowed.iter().filter(|&&t| EDGES[t] != 0).count()
+        });
+
+        assert!(model.start());
+        let before = counters(&constraint.borrow());
+        assert!(consistent(&model, &constraint.borrow()));
+
+        for (i, t) in [(12, 3), (2, 0), (20, 0), (0, 1)] {
+            assert!(model.decide(i, t));
+            assert!(consistent(&model, &constraint.borrow()));
+        }
+        for _ in 0..4 {
+            model.take_back();
+            assert!(consistent(&model, &constraint.borrow()));
+        }
+        assert!(counters(&constraint.borrow()) == before);
+    }
+}
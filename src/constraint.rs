@@ -0,0 +1,52 @@
+pub use crate::model::Wave;
+
+/// A rule that the neighbor constraints can't express, checked by the model inside its propagation loop.
+///
+/// The hooks can ban more tiles through the [`Wave`] they receive, and return `false` to signal a
+/// contradiction, which is handled like any other: the run fails, or backtracks when enabled.
+/// Tiles are the indices used by the model (see [`SimpleTiledModel::tile_names`](crate::SimpleTiledModel::tile_names)),
+/// cells are in row-major order.
+///
+/// ```no_run
+/// use rusting_wave_function::{SimpleTiledModel, constraint::{Constraint, Wave}};
+///
+/// /// Keeps the first row free of a tile
+/// struct NotOnTop(usize);
+///
+/// impl Constraint for NotOnTop {
+///     fn init(&mut self, wave: &mut Wave) -> bool {
+///         for x in 0..wave.width() {
+///             wave.ban(x, self.0);
+///         }
+///         true
+///     }
+/// }
+///
+/// let mut model = SimpleTiledModel::new(&"./tilesets/Castle.xml", 20)?;
+/// let tower = model.tile_names().iter().position(|name| name == "tower 0").unwrap();
+/// model.add_constraint(NotOnTop(tower));
+/// model.run(-1, [0u8; 32])?;
+/// # Ok::<(), rusting_wave_function::WfcError>(())
+/// ```
+pub trait Constraint {
+    /// Called at the start of every run, when every tile is allowed everywhere. The bans made here,
+    /// by any constraint, and those of the ground and the presets are all reported to
+    /// [`on_ban`](Self::on_ban) afterwards
+    fn init(&mut self, _wave: &mut Wave) -> bool {
+        true
+    }
+
+    /// Called after tile `tile` has been observed in cell `node` and its consequences propagated
+    fn on_observe(&mut self, _wave: &mut Wave, _node: usize, _tile: usize) -> bool {
+        true
+    }
+
+    /// Called when the propagation gets to the ban of tile `t` from cell `i`, whoever banned it
+    fn on_ban(&mut self, _wave: &mut Wave, _i: usize, _t: usize) -> bool {
+        true
+    }
+
+    /// Called when backtracking gives tile `t` back to cell `i`, for constraints that keep track of the bans.
+    /// Every ban undone has been seen by [`on_ban`](Self::on_ban) first.
+    fn on_unban(&mut self, _wave: &Wave, _i: usize, _t: usize) {}
+}
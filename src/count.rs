@@ -0,0 +1,295 @@
+use std::{fmt, str::FromStr};
+
+use crate::{constraint::{Constraint, Wave}, error::WfcError};
+
+/// A number of cells, either absolute or as a percentage of the grid
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Count {
+    Cells(usize),
+    Percent(f32),
+}
+
+impl Count {
+
+    /// Number of cells out of `cells`, rounding percentages up for a minimum and down for a maximum
+    fn resolve(self, cells: usize, round_up: bool) -> usize {
+        match self {
+            Count::Cells(count) => count,
+            Count::Percent(percent) => {
+                let count = percent / 100.0 * cells as f32;
+                if round_up { count.ceil() as usize } else { count.floor() as usize }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Count {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Count::Cells(count) => write!(f, "{count}"),
+            Count::Percent(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
+
+impl FromStr for Count {
+    type Err = WfcError;
+
+    /// `"3"` is 3 cells, `"10%"` a tenth of the grid
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || WfcError::InvalidParameter(format!("\"{value}\" is neither a number of cells nor a percentage"));
+        match value.trim().strip_suffix('%') {
+            Some(percent) => percent.trim().parse().ok().filter(|p| (0.0..=100.0).contains(p)).map(Count::Percent).ok_or_else(invalid),
+            None => value.trim().parse().map(Count::Cells).map_err(|_| invalid()),
+        }
+    }
+}
+
+/// Whether `min` is greater than `max` whatever the size of the grid, which is only known for two
+/// numbers of cells or two percentages
+pub fn unsatisfiable(min: Option<Count>, max: Option<Count>) -> bool {
+    match (min, max) {
+        (Some(Count::Cells(min)), Some(Count::Cells(max))) => min > max,
+        (Some(Count::Percent(min)), Some(Count::Percent(max))) => min > max,
+        _ => false,
+    }
+}
+
+/// Keeps the number of cells holding one of a group of tiles between a minimum and a maximum.
+///
+/// Once the cells left with nothing but tiles of the group reach the maximum, the group is banned
+/// from every other cell; once the cells that can still hold it are down to the minimum, every one
+/// of them is restricted to the group.
+pub struct CountConstraint {
+    group: Vec<usize>,
+    member: Vec<bool>,
+    min: Option<Count>,
+    max: Option<Count>,
+
+    min_cells: usize,
+    max_cells: usize,
+    /// Tiles allowed in each cell, and how many of them are in the group, as far as the reported bans go
+    allowed: Vec<usize>,
+    in_group: Vec<usize>,
+    /// Cells where only tiles of the group are left
+    certain: usize,
+    /// Cells where a tile of the group is still allowed
+    possible: usize,
+    /// Whether every cell has been handled since the maximum, or the minimum, was reached
+    swept_max: bool,
+    swept_min: bool,
+}
+
+impl CountConstraint {
+
+    /// `group` lists the tile indices counted together, `None` leaves a bound out
+    pub fn new(group: Vec<usize>, min: Option<Count>, max: Option<Count>) -> Self {
+        CountConstraint {
+            group,
+            member: Vec::new(),
+            min,
+            max,
+            min_cells: 0,
+            max_cells: 0,
+            allowed: Vec::new(),
+            in_group: Vec::new(),
+            certain: 0,
+            possible: 0,
+            swept_max: false,
+            swept_min: false,
+        }
+    }
+
+    fn is_certain(&self, i: usize) -> bool {
+        self.in_group[i] > 0 && self.in_group[i] == self.allowed[i]
+    }
+
+    fn is_possible(&self, i: usize) -> bool {
+        self.in_group[i] > 0
+    }
+
+    /// Checks the bounds and, when one of them is reached, bans what would cross it: from every cell
+    /// when the bound has just been reached, afterwards only from `changed`, the others being done
+    fn enforce(&mut self, wave: &mut Wave, changed: Option<usize>) -> bool {
+        if self.certain > self.max_cells || self.possible < self.min_cells {
+            return false;
+        }
+
+        let at_max = self.certain == self.max_cells;
+        let at_min = self.possible == self.min_cells;
+        if !at_max && !at_min {
+            return true;
+        }
+
+        let sweep = (at_max && !self.swept_max) || (at_min && !self.swept_min);
+        self.swept_max |= at_max;
+        self.swept_min |= at_min;
+        let cells = match changed {
+            Some(i) if !sweep => i..i + 1,
+            _ => 0..self.allowed.len(),
+        };
+
+        for i in cells {
+            if !self.is_possible(i) || self.is_certain(i) {
+                continue;
+            }
+            for t in 0..wave.tile_count() {
+                // At the maximum the undecided cells lose the group, at the minimum they lose everything else
+                if (at_max && self.member[t]) || (at_min && !self.member[t]) {
+                    wave.ban(i, t);
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Constraint for CountConstraint {
+    fn init(&mut self, wave: &mut Wave) -> bool {
+        let tiles = wave.tile_count();
+        // Percentages are taken of the cells that take part in the generation
+        let active: Vec<bool> = (0..wave.width() * wave.height()).map(|i| wave.is_active(i)).collect();
+        let cells = active.iter().filter(|&&active| active).count();
+
+        self.member = vec![false; tiles];
+        for &t in &self.group {
+            if let Some(member) = self.member.get_mut(t) {
+                *member = true;
+            }
+        }
+        let group_size = self.member.iter().filter(|&&member| member).count();
+
+        self.min_cells = self.min.map_or(0, |min| min.resolve(cells, true));
+        self.max_cells = self.max.map_or(cells, |max| max.resolve(cells, false));
+        self.allowed = vec![tiles; active.len()];
+        self.in_group = active.iter().map(|&active| if active { group_size } else { 0 }).collect();
+        self.certain = if group_size == tiles { cells } else { 0 };
+        self.possible = if group_size > 0 { cells } else { 0 };
+        self.swept_max = false;
+        self.swept_min = false;
+
+        // Bounds mixing cells and percentages can only be compared now that the size of the grid is known
+        if self.min_cells > self.max_cells {
+            return false;
+        }
+        self.enforce(wave, None)
+    }
+
+    fn on_ban(&mut self, wave: &mut Wave, i: usize, t: usize) -> bool {
+        let (was_certain, was_possible) = (self.is_certain(i), self.is_possible(i));
+
+        self.allowed[i] -= 1;
+        if self.member[t] {
+            self.in_group[i] -= 1;
+        }
+
+        let (certain, possible) = (self.is_certain(i), self.is_possible(i));
+        if certain == was_certain && possible == was_possible {
+            return true;
+        }
+        self.certain = self.certain + certain as usize - was_certain as usize;
+        self.possible = self.possible + possible as usize - was_possible as usize;
+
+        self.enforce(wave, Some(i))
+    }
+
+    fn on_unban(&mut self, _wave: &Wave, i: usize, t: usize) {
+        let (was_certain, was_possible) = (self.is_certain(i), self.is_possible(i));
+
+        self.allowed[i] += 1;
+        if self.member[t] {
+            self.in_group[i] += 1;
+        }
+
+        self.certain = self.certain + self.is_certain(i) as usize - was_certain as usize;
+        self.possible = self.possible + self.is_possible(i) as usize - was_possible as usize;
+        // A bound left behind has to be swept again when it's reached anew
+        self.swept_max &= self.certain == self.max_cells;
+        self.swept_min &= self.possible == self.min_cells;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Model, seed_bytes};
+
+    /// A `width` x `height` wave of two tiles that can sit anywhere, 1 being `weight` times as
+    /// likely as 0, limited by `count`
+    fn model(width: usize, height: usize, weight: f32, count: CountConstraint) -> Model {
+        let mut model = Model::new(width, height, 1, vec![1.0, weight], vec![vec![vec![0, 1]; 2]; 4]).unwrap();
+        model.add_constraint(count);
+        model
+    }
+
+    fn count(model: &Model, tile: usize) -> usize {
+        model.observed().iter().filter(|&&observed| observed == Some(tile)).count()
+    }
+
+    #[test]
+    fn a_maximum_of_one_allows_a_single_tile() {
+        for seed in 0..10 {
+            let mut model = model(6, 5, 10.0, CountConstraint::new(vec![1], None, Some(Count::Cells(1))));
+            model.run(-1, seed_bytes(seed)).unwrap();
+            assert_eq!(count(&model, 1), 1, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn a_minimum_forces_placements() {
+        for seed in 0..10 {
+            let mut model = model(6, 5, 0.001, CountConstraint::new(vec![1], Some(Count::Cells(7)), None));
+            model.run(-1, seed_bytes(seed)).unwrap();
+            assert!(count(&model, 1) >= 7, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn percentages_are_taken_of_the_grid() {
+        // 25% and 50% of 20 cells
+        for (weight, expected) in [(0.001, 5), (1000.0, 10)] {
+            let mut model = model(5, 4, weight, CountConstraint::new(vec![1], Some(Count::Percent(25.0)), Some(Count::Percent(50.0))));
+            model.run(-1, seed_bytes(0)).unwrap();
+            assert_eq!(count(&model, 1), expected);
+        }
+
+        // At least 3 of 25 cells, but at most 2
+        let mut model = model(5, 5, 1.0, CountConstraint::new(vec![1], Some(Count::Percent(10.0)), Some(Count::Percent(10.0))));
+        assert!(matches!(model.run(-1, seed_bytes(0)), Err(WfcError::Contradiction)));
+    }
+
+    #[test]
+    fn maximums_reached_again_after_an_undo_are_swept_again() {
+        let mut model = model(3, 1, 1.0, CountConstraint::new(vec![1], None, Some(Count::Cells(1))));
+        assert!(model.start());
+        assert!(model.decide(0, 1));
+        assert!(!model.is_allowed(2, 1));
+        model.take_back();
+        assert!(model.is_allowed(2, 1));
+        assert!(model.decide(2, 1));
+        assert!(!model.is_allowed(0, 1) && !model.is_allowed(1, 1));
+    }
+
+    #[test]
+    fn minimums_reached_again_after_an_undo_are_swept_again() {
+        let mut model = model(3, 1, 1.0, CountConstraint::new(vec![1], Some(Count::Cells(2)), None));
+        assert!(model.start());
+        assert!(model.decide(0, 0));
+        assert!(!model.is_allowed(2, 0));
+        model.take_back();
+        assert!(model.is_allowed(2, 0));
+        assert!(model.decide(1, 0));
+        assert!(!model.is_allowed(0, 0) && !model.is_allowed(2, 0));
+    }
+
+    #[test]
+    fn min_greater_than_max() {
+        assert!(unsatisfiable(Some(Count::Cells(5)), Some(Count::Cells(2))));
+        assert!(unsatisfiable(Some(Count::Percent(50.0)), Some(Count::Percent(20.0))));
+        assert!(!unsatisfiable(Some(Count::Cells(5)), Some(Count::Percent(20.0))));
+        assert!(!unsatisfiable(Some(Count::Cells(5)), None));
+
+        let mut model = model(5, 4, 1.0, CountConstraint::new(vec![1], Some(Count::Cells(10)), Some(Count::Percent(25.0))));
+        assert!(matches!(model.run(-1, seed_bytes(0)), Err(WfcError::Contradiction)));
+    }
+}
@@ -0,0 +1,93 @@
+use std::{error::Error, fmt, io};
+
+/// Everything that can go wrong while loading a tileset or a sample and generating from it
+#[derive(Debug)]
+pub enum WfcError {
+    /// Reading or writing a file failed
+    Io(io::Error),
+    /// An image couldn't be decoded or encoded
+    Image(image::ImageError),
+    /// The tileset isn't well formed XML
+    Xml(roxmltree::Error),
+    /// A map isn't well formed JSON
+    Json(serde_json::Error),
+    /// A tag or attribute required by the tileset is missing
+    MissingXml(String),
+    /// A tile name (with its optional variant) that the tileset doesn't define
+    UnknownTile(String),
+    /// A subset that the tileset doesn't define
+    UnknownSubset(String),
+    /// A channel that no tile of the tileset declares
+    UnknownChannel(String),
+    /// A `symmetry` attribute other than X, L, T, I, \ and F
+    BadSymmetry { tile: String, symmetry: String },
+    /// A tile image that isn't square or doesn't have the same size as the others
+    TileSizeMismatch { tile: String, width: u32, height: u32, expected: u32 },
+    /// An array that should represent a square grid doesn't have a square length
+    NotSquare(usize),
+    /// A parameter outside of its valid range
+    InvalidParameter(String),
+    /// The wave reached a state where some cell can't hold any tile
+    Contradiction,
+}
+
+impl fmt::Display for WfcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WfcError::Io(e) => write!(f, "I/O error: {e}"),
+            WfcError::Image(e) => write!(f, "image error: {e}"),
+            WfcError::Xml(e) => write!(f, "XML error: {e}"),
+            WfcError::Json(e) => write!(f, "JSON error: {e}"),
+            WfcError::MissingXml(what) => write!(f, "{what} not found in the document!"),
+            WfcError::UnknownTile(name) => write!(f, "unknown tile \"{name}\""),
+            WfcError::UnknownSubset(name) => write!(f, "subset {name} not found in the document!"),
+            WfcError::UnknownChannel(name) => write!(f, "no tile declares channel \"{name}\""),
+            WfcError::BadSymmetry { tile, symmetry } => write!(f, "tile \"{tile}\" has unknown symmetry \"{symmetry}\""),
+            WfcError::TileSizeMismatch { tile, width, height, expected } => {
+                write!(f, "tile \"{tile}\" is {width}x{height}, expected {expected}x{expected}")
+            }
+            WfcError::NotSquare(len) => write!(f, "an array of {len} elements doesn't represent a square grid"),
+            WfcError::InvalidParameter(message) => write!(f, "{message}"),
+            WfcError::Contradiction => write!(f, "CONTRADICTION"),
+        }
+    }
+}
+
+impl Error for WfcError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WfcError::Io(e) => Some(e),
+            WfcError::Image(e) => Some(e),
+            WfcError::Xml(e) => Some(e),
+            WfcError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WfcError {
+    fn from(e: io::Error) -> Self {
+        WfcError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for WfcError {
+    fn from(e: image::ImageError) -> Self {
+        match e {
+            image::ImageError::IoError(e) => WfcError::Io(e),
+            e => WfcError::Image(e),
+        }
+    }
+}
+
+impl From<roxmltree::Error> for WfcError {
+    fn from(e: roxmltree::Error) -> Self {
+        WfcError::Xml(e)
+    }
+}
+
+impl From<serde_json::Error> for WfcError {
+    fn from(e: serde_json::Error) -> Self {
+        WfcError::Json(e)
+    }
+}
@@ -0,0 +1,35 @@
+//! Wave Function Collapse, based on the SimpleTiledModel and OverlappingModel of the
+//! [original repository](https://github.com/mxgmn/WaveFunctionCollapse).
+//!
+//! ```no_run
+//! use rusting_wave_function::SimpleTiledModel;
+//!
+//! let mut model = SimpleTiledModel::new(&"./tilesets/Castle.xml", 20)?;
+//! model.run(-1, [0u8; 32])?;
+//! model.save("castle.png")?;
+//! println!("{}", model.text_output());
+//! # Ok::<(), rusting_wave_function::WfcError>(())
+//! ```
+
+pub mod array_utils;
+pub mod bitmap_utils;
+pub mod chunks;
+pub mod connectivity;
+pub mod constraint;
+pub mod count;
+pub mod error;
+pub mod model;
+pub mod overlapping;
+pub mod simple_tiled;
+pub mod tile_map;
+pub mod validate;
+
+pub use chunks::{Chunk, ChunkGenerator};
+pub use connectivity::ConnectivityConstraint;
+pub use constraint::{Constraint, Wave};
+pub use count::{Count, CountConstraint};
+pub use error::WfcError;
+pub use model::{Generator, Heuristic, Model, Side, derive_seed, seed_bytes};
+pub use overlapping::OverlappingModel;
+pub use simple_tiled::SimpleTiledModel;
+pub use tile_map::{Margins, TileMap};
@@ -1,22 +1,143 @@
-use simple_tiled::SimpleTiledModel;
-use clap::Parser;
+use rusting_wave_function::{ChunkGenerator, Count, Generator, Heuristic, Margins, OverlappingModel, Side, SimpleTiledModel, TileMap, WfcError, bitmap_utils, count};
+use rusting_wave_function::validate::{Severity, validate_tileset};
+use clap::{Parser, Subcommand, ValueEnum};
 use rand::{Rng};
-mod simple_tiled;
-mod bitmap_utils;
-mod array_utils;
 
 /// Parametri da linea di comando
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(author, version, about, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Model used for the generation
+    #[arg(short, long, value_enum, default_value_t = ModelKind::SimpleTiled)]
+    model: ModelKind,
+
     /// Tileset name, choose between XML files
     #[arg(short, long, default_value = "Summer")]
     tileset: String,
 
+    /// Subset of the tileset to use, as named in its <subsets> section
+    #[arg(long)]
+    subset: Option<String>,
+
+    /// File of cells to pin before generating, one "<x> <y> <tile> [variant]" per line (simple tiled model only)
+    #[arg(long)]
+    pins: Option<String>,
+
+    /// Previous result to regenerate in part, as saved by --save-map (simple tiled model only)
+    #[arg(long)]
+    inpaint: Option<String>,
+
+    /// Rectangle of cells to regenerate with --inpaint, as "x,y,width,height"
+    #[arg(long, requires = "inpaint", value_parser = parse_rect)]
+    region: Option<[usize; 4]>,
+
+    /// Black and white image with one pixel per cell, the white cells are regenerated with --inpaint
+    #[arg(long, requires = "inpaint")]
+    mask: Option<String>,
+
+    /// Previous result to grow with --extend, as saved by --save-map (simple tiled model only)
+    #[arg(long, conflicts_with = "inpaint")]
+    outpaint: Option<String>,
+
+    /// Side and number of cells to add with --outpaint, e.g. "east:20" (repeatable)
+    #[arg(long, requires = "outpaint", value_parser = parse_extension)]
+    extend: Vec<(Side, usize)>,
+
+    /// Also save the generated tile names, as JSON if the name ends in .json (simple tiled model only)
+    #[arg(long)]
+    save_map: Option<String>,
+
+    /// Number of cells holding some tiles, as "<tiles>=<min>..<max>" with comma separated tiles and
+    /// optional bounds in cells or percent, e.g. "tower=1..1" or "river,riverturn=10%.." (repeatable)
+    #[arg(long, value_parser = parse_count_limit)]
+    count: Vec<CountLimit>,
+
+    /// Channel declared by the tileset, such as "road", whose cells must all form one network (repeatable)
+    #[arg(long)]
+    connect: Vec<String>,
+
+    /// Tile framing a side of the grid, as "<side>=<tile>" where the side can also be "all", e.g. "all=water_a" (repeatable)
+    #[arg(long, value_parser = parse_boundary)]
+    boundary: Vec<(Vec<Side>, String)>,
+
+    /// Cells that must carry a channel and be linked to each other, as "<channel>:<x>,<y>:<x>,<y>..." (repeatable)
+    #[arg(long, value_parser = parse_points)]
+    connect_points: Vec<(String, Vec<(usize, usize)>)>,
+
+    /// Image scaling the weights of some tiles cell by cell, as "<tiles>:<image>[:<strength>]" with comma
+    /// separated tiles and one pixel per cell, white favoring them and black avoiding them (repeatable)
+    #[arg(long, value_parser = parse_weight_map)]
+    weight_map: Vec<WeightMap>,
+
+    /// Image painting the region of each cell with the colors of the <region> tags of the tileset,
+    /// one pixel per cell, transparent pixels leaving the cell free (simple tiled model only)
+    #[arg(long)]
+    regions: Option<String>,
+
+    /// Black and white image limiting the generation to its white cells, one pixel per cell, the others
+    /// being left empty and treated as outside of the grid (simple tiled model only)
+    #[arg(long)]
+    active: Option<String>,
+
+    /// Sample image the overlapping model learns its patterns from
+    #[arg(long)]
+    sample: Option<String>,
+
+    /// Size of the NxN patterns learned by the overlapping model
+    #[arg(short = 'N', long, default_value_t = 3)]
+    pattern_size: usize,
+
+    /// Number of rotated and reflected copies of each pattern (1 to 8, overlapping model only)
+    #[arg(long, default_value_t = 8)]
+    symmetry: usize,
+
+    /// Patterns wrap around the borders of the sample (overlapping model only)
+    #[arg(long, default_value_t = false)]
+    periodic_input: bool,
+
+    /// Fill the bottom row with the last pattern of the sample (overlapping model only)
+    #[arg(long, default_value_t = false)]
+    ground: bool,
+
     /// Grid dimension (doesn't correspond to image size, depends on tile size)
     #[arg(short, long, default_value_t = 10)]
     size: usize,
 
+    /// Grid width, overrides --size
+    #[arg(long)]
+    width: Option<usize>,
+
+    /// Grid height, overrides --size
+    #[arg(long)]
+    height: Option<usize>,
+
+    /// Wrap the output around both axes, so that it tiles seamlessly
+    #[arg(long, default_value_t = false)]
+    periodic: bool,
+
+    /// Wrap the output around horizontally only
+    #[arg(long, default_value_t = false)]
+    periodic_x: bool,
+
+    /// Wrap the output around vertically only
+    #[arg(long, default_value_t = false)]
+    periodic_y: bool,
+
+    /// How the next cell to observe is chosen
+    #[arg(long, value_enum, default_value_t = HeuristicKind::Entropy)]
+    heuristic: HeuristicKind,
+
+    /// On contradiction, take back the last observations (at most this many times) instead of giving up
+    #[arg(long)]
+    backtrack: Option<usize>,
+
+    /// On contradiction, run again up to this many times with seeds derived from --seed
+    #[arg(long, default_value_t = 0)]
+    retries: usize,
+
     /// Iterations (-1 means go until you reach an end state)
     #[arg(short, long, default_value_t = -1)]
     limit: isize,
@@ -34,34 +155,400 @@ struct Args {
     output: String,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check a tileset and report every problem found, with its position in the XML
+    Validate {
+        /// Tileset name, choose between XML files
+        #[arg(short, long, default_value = "Summer")]
+        tileset: String,
+    },
+    /// Generate a block of chunks of an unbounded world, each matching the chunks around it
+    Chunks {
+        /// Tileset name, choose between XML files
+        #[arg(short, long, default_value = "Summer")]
+        tileset: String,
+
+        /// Subset of the tileset to use, as named in its <subsets> section
+        #[arg(long)]
+        subset: Option<String>,
+
+        /// Side of each chunk, in tiles
+        #[arg(long, default_value_t = 16)]
+        chunk_size: usize,
+
+        /// Coordinates of the top left chunk, as "cx,cy"
+        #[arg(long, default_value = "0,0", value_parser = parse_chunk, allow_hyphen_values = true)]
+        from: (i64, i64),
+
+        /// Chunks to generate, as "columns,rows"
+        #[arg(long, default_value = "2,2", value_parser = parse_count)]
+        count: (usize, usize),
+
+        /// World seed (if not specified, randomly generated)
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// On contradiction, take back the last observations (at most this many times) instead of giving up
+        #[arg(long)]
+        backtrack: Option<usize>,
+
+        /// On contradiction, run a chunk again up to this many times before relaxing its borders
+        #[arg(long, default_value_t = 0)]
+        retries: usize,
+
+        /// Name of the .png output
+        #[arg(short, long, default_value = "output.png")]
+        output: String,
+
+        /// Also save the generated tile names, as JSON if the name ends in .json
+        #[arg(long)]
+        save_map: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct CountLimit {
+    tiles: Vec<String>,
+    min: Option<Count>,
+    max: Option<Count>,
+}
+
+#[derive(Clone, Debug)]
+struct WeightMap {
+    tiles: Vec<String>,
+    path: String,
+    strength: f32,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ModelKind {
+    SimpleTiled,
+    Overlapping,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum HeuristicKind {
+    /// Lowest Shannon entropy first, as in the original algorithm
+    Entropy,
+    /// Fewest remaining tiles first
+    Mrv,
+    /// Row by row from the top left corner
+    Scanline,
+    /// Outwards from the center
+    Spiral,
+    /// Random order
+    Random,
+}
+
+impl From<HeuristicKind> for Heuristic {
+    fn from(kind: HeuristicKind) -> Self {
+        match kind {
+            HeuristicKind::Entropy => Heuristic::Entropy,
+            HeuristicKind::Mrv => Heuristic::Mrv,
+            HeuristicKind::Scanline => Heuristic::Scanline,
+            HeuristicKind::Spiral => Heuristic::Spiral,
+            HeuristicKind::Random => Heuristic::Random,
+        }
+    }
+}
+
+fn main() {
     let args = Args::parse();
 
-    let xml_path = format!("./tilesets/{}.xml", args.tileset);
-    let mut model = SimpleTiledModel::new(&xml_path, args.size)?;
+    let result = match &args.command {
+        Some(Command::Validate { tileset }) => validate(tileset),
+        Some(Command::Chunks { tileset, subset, chunk_size, from, count, seed, backtrack, retries, output, save_map }) => {
+            generate_chunks(tileset, subset.as_deref(), *chunk_size, *from, *count, *seed, *backtrack, *retries, output, save_map.as_deref()).map(|()| true)
+        }
+        None => generate_from_args(&args).map(|()| true),
+    };
+
+    match result {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints the diagnostics of the tileset, returns whether it's free of errors
+fn validate(tileset: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let xml_path = format!("./tilesets/{}.xml", tileset);
+    let diagnostics = validate_tileset(&xml_path)?;
+
+    for diagnostic in &diagnostics {
+        println!("{xml_path}:{diagnostic}");
+    }
+
+    let errors = diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+    println!("{} errors, {} warnings", errors, diagnostics.len() - errors);
+    Ok(errors == 0)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn generate_chunks(tileset: &str, subset: Option<&str>, chunk_size: usize, (cx, cy): (i64, i64), (columns, rows): (usize, usize),
+                   seed: Option<u64>, backtrack: Option<usize>, retries: usize, output: &str, save_map: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let seed = seed.unwrap_or_else(|| rand::rng().random());
+    let xml_path = format!("./tilesets/{}.xml", tileset);
+
+    let mut world = ChunkGenerator::new(&xml_path, subset, chunk_size, seed)?;
+    world.set_backtracking(backtrack);
+    world.set_retries(retries);
+
+    println!("World seed: {seed}");
+    for y in cy..cy + rows as i64 {
+        for x in cx..cx + columns as i64 {
+            let chunk = world.generate(x, y)?;
+            if chunk.seams > 0 {
+                println!("Chunk ({x}, {y}): seed {}, border cells that don't match: {}", chunk.seed, chunk.seams);
+            } else {
+                println!("Chunk ({x}, {y}): seed {}", chunk.seed);
+            }
+        }
+    }
+
+    world.save(output, cx, cy, columns, rows)?;
+    if let Some(path) = save_map {
+        world.tile_map(cx, cy, columns, rows).save(&path)?;
+    }
+    Ok(())
+}
+
+fn generate_from_args(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
 
     // Seed generation
-    let seed_arr: [u8; 32] = if let Some(seed) = args.seed {
-        let mut arr = [0u8; 32];
-        arr[..8].copy_from_slice(&seed.to_le_bytes());
-        arr
-    } else {
-        let mut arr = [0u8; 32];
-        rand::rng().fill(&mut arr);
-        arr
-    };
+    let seed = args.seed.unwrap_or_else(|| rand::rng().random());
+
+    let width = args.width.unwrap_or(args.size);
+    let height = args.height.unwrap_or(args.size);
 
-    let success = model.run(args.limit, seed_arr);
+    match args.model {
+        ModelKind::SimpleTiled => {
+            let xml_path = format!("./tilesets/{}.xml", args.tileset);
+            let map = args.inpaint.as_ref().map(TileMap::load).transpose()?;
+            let (width, height) = map.as_ref().map_or((width, height), |map| (map.width(), map.height()));
 
-    if success {
-        println!("Success!:");
-        println!("-------------------");
-        model.save(&args.output);
-        if args.text {
-            println!("{}", model.text_output());
+            let mut model = match &args.outpaint {
+                Some(path) => SimpleTiledModel::outpaint(&xml_path, args.subset.as_deref(), &TileMap::load(path)?, margins(args)?)?,
+                None => SimpleTiledModel::with_subset(&xml_path, args.subset.as_deref(), width, height)?,
+            };
+            for limit in &args.count {
+                let tiles: Vec<&str> = limit.tiles.iter().map(String::as_str).collect();
+                model.limit_count(&tiles, limit.min, limit.max)?;
+            }
+            for map in &args.weight_map {
+                let tiles: Vec<&str> = map.tiles.iter().map(String::as_str).collect();
+                model.weight_map(&map.path, &tiles, map.strength)?;
+            }
+            if let Some(active) = &args.active {
+                model.set_active_mask(active)?;
+            }
+            if let Some(regions) = &args.regions {
+                model.restrict_regions(regions)?;
+            }
+            for (sides, tile) in &args.boundary {
+                for &side in sides {
+                    model.set_boundary(side, Some(tile))?;
+                }
+            }
+            for channel in &args.connect {
+                model.connect(channel)?;
+            }
+            for (channel, points) in &args.connect_points {
+                model.connect_points(channel, points)?;
+            }
+            if let Some(pins) = &args.pins {
+                model.pin_from_file(pins)?;
+            }
+            if let Some(map) = &map {
+                let regenerate = inpaint_mask(args, width, height)?;
+                model.pin_map(map, 0, 0, |x, y| regenerate[x + y * width])?;
+            }
+            if generate(&mut model, args, seed)? && let Some(path) = &args.save_map {
+                model.tile_map().save(path)?;
+            }
+        }
+        ModelKind::Overlapping => {
+            if args.pins.is_some() || args.inpaint.is_some() || args.outpaint.is_some() || args.save_map.is_some() || !args.count.is_empty()
+                || !args.connect.is_empty() || !args.connect_points.is_empty() || !args.boundary.is_empty() || !args.weight_map.is_empty() || args.regions.is_some() || args.active.is_some() {
+                return Err("--pins, --inpaint, --outpaint, --save-map, --count, --connect, --connect-points, --boundary, --weight-map, --regions and --active only work with the simple tiled model".into());
+            }
+            let sample = args.sample.as_ref().ok_or("The overlapping model needs a --sample image")?;
+            let mut model = OverlappingModel::with_dimensions(sample, args.pattern_size, width, height, args.periodic_input, args.symmetry, args.ground)?;
+            generate(&mut model, args, seed)?;
         }
-    } else {
-        println!("CONTRADICTION");
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Cells to regenerate when inpainting: the union of --region and --mask
+fn inpaint_mask(args: &Args, width: usize, height: usize) -> Result<Vec<bool>, Box<dyn std::error::Error>> {
+    if args.region.is_none() && args.mask.is_none() {
+        return Err("--inpaint needs a --region or a --mask to regenerate".into());
+    }
+
+    let mut regenerate = vec![false; width * height];
+    if let Some([x0, y0, w, h]) = args.region {
+        for y in y0..(y0 + h).min(height) {
+            for x in x0..(x0 + w).min(width) {
+                regenerate[x + y * width] = true;
+            }
+        }
+    }
+    if let Some(path) = &args.mask {
+        let (mask, mask_width, mask_height) = bitmap_utils::load_mask(path)?;
+        if (mask_width as usize, mask_height as usize) != (width, height) {
+            return Err(format!("The mask is {mask_width}x{mask_height}, the map is {width}x{height}").into());
+        }
+        for (cell, masked) in regenerate.iter_mut().zip(mask) {
+            *cell |= masked;
+        }
+    }
+    Ok(regenerate)
+}
+
+/// Cells added on each side with --extend
+fn margins(args: &Args) -> Result<Margins, Box<dyn std::error::Error>> {
+    if args.extend.is_empty() {
+        return Err("--outpaint needs at least one --extend".into());
+    }
+
+    let mut margins = Margins::default();
+    for &(side, cells) in &args.extend {
+        match side {
+            Side::Left => margins.left += cells,
+            Side::Top => margins.top += cells,
+            Side::Right => margins.right += cells,
+            Side::Bottom => margins.bottom += cells,
+        }
+    }
+    Ok(margins)
+}
+
+fn parse_extension(value: &str) -> Result<(Side, usize), String> {
+    let (side, cells) = value.split_once(':').ok_or("expected <side>:<cells>, e.g. east:20")?;
+    let cells = cells.trim().parse().map_err(|e: std::num::ParseIntError| e.to_string())?;
+    Ok((parse_side(side)?, cells))
+}
+
+fn parse_boundary(value: &str) -> Result<(Vec<Side>, String), String> {
+    let (side, tile) = value.split_once('=').ok_or("expected <side>=<tile>, e.g. all=water_a")?;
+    let sides = match side.trim().to_lowercase().as_str() {
+        "all" => Side::ALL.to_vec(),
+        _ => vec![parse_side(side)?],
+    };
+    Ok((sides, tile.trim().to_string()))
+}
+
+fn parse_side(value: &str) -> Result<Side, String> {
+    match value.trim().to_lowercase().as_str() {
+        "left" | "west" => Ok(Side::Left),
+        "top" | "north" => Ok(Side::Top),
+        "right" | "east" => Ok(Side::Right),
+        "bottom" | "south" => Ok(Side::Bottom),
+        other => Err(format!("unknown side \"{other}\", expected left, top, right, bottom or west, north, east, south")),
+    }
+}
+
+fn parse_count_limit(value: &str) -> Result<CountLimit, String> {
+    let (tiles, bounds) = value.split_once('=').ok_or("expected <tiles>=<min>..<max>, e.g. tower=1..1")?;
+    let (min, max) = bounds.split_once("..").ok_or("expected <min>..<max>, either of them can be left out")?;
+    let bound = |v: &str| (!v.trim().is_empty()).then(|| v.parse::<Count>()).transpose().map_err(|e| e.to_string());
+
+    let (min, max) = (bound(min)?, bound(max)?);
+    if let (Some(min), Some(max)) = (min, max) && count::unsatisfiable(Some(min), Some(max)) {
+        return Err(format!("the minimum {min} is greater than the maximum {max}"));
+    }
+
+    Ok(CountLimit {
+        tiles: tiles.split(',').map(|tile| tile.trim().to_string()).collect(),
+        min,
+        max,
+    })
+}
+
+fn parse_points(value: &str) -> Result<(String, Vec<(usize, usize)>), String> {
+    let mut parts = value.split(':');
+    let channel = parts.next().unwrap_or_default().trim().to_string();
+    let points = parts
+        .map(|point| {
+            let (x, y) = point.split_once(',').ok_or("expected <channel>:<x>,<y>:<x>,<y>..., e.g. road:0,5:19,12")?;
+            let parse = |v: &str| v.trim().parse::<usize>().map_err(|e| e.to_string());
+            Ok((parse(x)?, parse(y)?))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if channel.is_empty() || points.len() < 2 {
+        return Err("expected a channel and at least two points, e.g. road:0,5:19,12".to_string());
+    }
+    Ok((channel, points))
+}
+
+fn parse_weight_map(value: &str) -> Result<WeightMap, String> {
+    let (tiles, rest) = value.split_once(':').ok_or("expected <tiles>:<image>[:<strength>], e.g. water_a:density.png:2")?;
+    let (path, strength) = match rest.rsplit_once(':') {
+        Some((path, strength)) => (path, strength.trim().parse::<f32>().map_err(|e| e.to_string())?),
+        None => (rest, 1.0),
+    };
+
+    Ok(WeightMap {
+        tiles: tiles.split(',').map(|tile| tile.trim().to_string()).collect(),
+        path: path.to_string(),
+        strength,
+    })
+}
+
+fn parse_chunk(value: &str) -> Result<(i64, i64), String> {
+    let (x, y) = value.split_once(',').ok_or("expected cx,cy")?;
+    let parse = |v: &str| v.trim().parse::<i64>().map_err(|e| e.to_string());
+    Ok((parse(x)?, parse(y)?))
+}
+
+fn parse_count(value: &str) -> Result<(usize, usize), String> {
+    let (x, y) = value.split_once(',').ok_or("expected columns,rows")?;
+    let parse = |v: &str| v.trim().parse::<usize>().map_err(|e| e.to_string());
+    Ok((parse(x)?, parse(y)?))
+}
+
+fn parse_rect(value: &str) -> Result<[usize; 4], String> {
+    let parts: Vec<usize> = value.split(',')
+        .map(|part| part.trim().parse::<usize>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())?;
+    parts.try_into().map_err(|_| "expected x,y,width,height".to_string())
+}
+
+/// Runs the generation and saves its output, returns whether it succeeded
+fn generate<G: Generator>(model: &mut G, args: &Args, seed: u64) -> Result<bool, WfcError> {
+    model.model_mut().set_periodic(args.periodic || args.periodic_x, args.periodic || args.periodic_y);
+    model.model_mut().set_backtracking(args.backtrack);
+    model.model_mut().set_heuristic(args.heuristic.into());
+
+    let result = model.model_mut().run_with_retries(args.limit, seed, args.retries);
+
+    match result {
+        Ok((attempt, attempt_seed)) => {
+            println!("Success!:");
+            if args.retries > 0 {
+                println!("Attempt {} of {}, seed {} (reproduce with --seed {})", attempt + 1, args.retries + 1, attempt_seed, attempt_seed);
+            }
+            if args.backtrack.is_some() {
+                println!("Backtracks: {}", model.model().backtracks());
+            }
+            println!("-------------------");
+            model.save(&args.output)?;
+            if args.text {
+                println!("{}", model.text_output());
+            }
+        }
+        Err(WfcError::Contradiction) => {
+            println!("CONTRADICTION");
+            return Ok(false);
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(true)
+}
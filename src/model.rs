@@ -0,0 +1,1167 @@
+use core::f32;
+use rand::Rng;
+use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use crate::{array_utils, constraint::Constraint, error::WfcError};
+
+/// State of the algorithm shared by every model: the wave, the propagator built by the
+/// specific model and the bookkeeping needed by observe, propagate and ban.
+pub struct Model {
+    propagator: Vec<Vec<Vec<usize>>>,
+    state: State,
+    constraints: Vec<Box<dyn Constraint>>,
+}
+
+/// The wave as seen by a [`Constraint`]: queries on its cells, in row-major order, and bans that
+/// are propagated like the ones made by the algorithm itself
+pub struct Wave<'a> {
+    state: &'a mut State,
+}
+
+/// Everything in [`Model`] but the propagator, kept apart so that propagate can go through the
+/// propagator lists while banning tiles.
+///
+/// The per-cell state lives in flat buffers: the wave is a bitset of `words` u64 per cell, and
+/// `compatible` holds 4 counters per cell and tile, at `(i * t + tile) * 4 + d`.
+struct State {
+    wave: Vec<u64>,
+    words: usize,
+    observed: Vec<Option<usize>>,
+
+    compatible: Vec<i32>,
+    initial_compatible: Vec<i32>,
+
+    stack: Vec<(usize, usize)>,
+
+    presets: Vec<Option<Vec<bool>>>,
+    /// Cells taking part in the generation, every cell when unset
+    active: Option<Vec<bool>>,
+
+    backtrack_budget: Option<usize>,
+    backtracks: usize,
+    journal: Vec<Change>,
+    decisions: Vec<Decision>,
+
+    m_x: usize,
+    m_y: usize,
+    t: usize,
+    n: usize,
+    periodic_x: bool,
+    periodic_y: bool,
+    ground: bool,
+    /// Tiles allowed along each side of the grid by the tiles outside of it, indexed by direction
+    boundary: [Option<Vec<bool>>; 4],
+
+    weights: Vec<f32>,
+    weight_log_weights: Vec<f32>,
+    /// Weight of each tile in each cell, at `i * t + tile`, replacing `weights` when set
+    cell_weights: Option<Vec<f32>>,
+    cell_weight_log_weights: Vec<f32>,
+    distribution: Vec<f32>,
+
+    sums_of_ones: Vec<usize>,
+    sum_of_weights: f32,
+    sum_of_weight_log_weights: f32,
+    starting_entropy: f32,
+
+    sums_of_weights: Vec<f32>,
+    sums_of_weight_log_weights: Vec<f32>,
+    entropies: Vec<f32>,
+
+    heuristic: Heuristic,
+    noise: Vec<f32>,
+    queue: BinaryHeap<Candidate>,
+    order: Vec<usize>,
+    cursor: usize,
+}
+
+/// How the next cell to observe is chosen
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Heuristic {
+    /// Lowest Shannon entropy of the weights of the tiles left, as in the original algorithm
+    #[default]
+    Entropy,
+    /// Fewest tiles left (minimum remaining values), whatever their weights
+    Mrv,
+    /// Row by row, starting from the top left corner
+    Scanline,
+    /// Ring by ring, from the center of the grid outwards
+    Spiral,
+    /// A random order, drawn from the seed at the start of every run
+    Random,
+}
+
+/// Side of the grid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Bottom,
+    Right,
+    Top,
+}
+
+impl Side {
+    /// Every side, in the order of the directions they face
+    pub const ALL: [Side; 4] = [Side::Left, Side::Bottom, Side::Right, Side::Top];
+
+    /// Direction from the cells along the side to the outside of the grid
+    fn direction(self) -> usize {
+        self as usize
+    }
+}
+
+/// Undoable modification of the wave, recorded while backtracking is enabled
+enum Change {
+    Ban { i: usize, t: usize, compatible: [i32; 4] },
+    Decrement { i: usize, t: usize, d: usize },
+}
+
+/// An observation that can be taken back: where the journal stood before it, the cell and the chosen tile
+struct Decision {
+    journal_len: usize,
+    node: usize,
+    tile: usize,
+}
+
+/// Cell waiting to be observed, with the priority (entropy or remaining tiles) it had when it was queued.
+/// The queue is ordered by lowest priority first, ties are broken by the noise of the cells and then by
+/// their index. Entries are never removed when the priority of their cell changes, a new one is queued
+/// instead and the stale ones are skipped.
+#[derive(PartialEq)]
+struct Candidate {
+    priority: f32,
+    noise: f32,
+    node: usize,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.priority.total_cmp(&self.priority)
+            .then_with(|| other.noise.total_cmp(&self.noise))
+            .then_with(|| other.node.cmp(&self.node))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Common interface of the models built on top of [`Model`]
+pub trait Generator {
+    /// The underlying wave
+    fn model(&self) -> &Model;
+
+    fn model_mut(&mut self) -> &mut Model;
+
+    /// Saves the generated output as an image at `path`
+    fn save(&self, path: &str) -> Result<(), WfcError>;
+
+    /// Textual representation of the generated output
+    fn text_output(&self) -> String;
+}
+
+impl Model {
+
+    /// `n` is the size of the patterns: cells closer than `n` to the right or bottom border are never observed.
+    /// `propagator[d][t]` lists the tiles that can be placed next to `t` in direction `d`.
+    /// Fails with [`WfcError::InvalidParameter`] if the grid has no cells.
+    pub(crate) fn new(width: usize, height: usize, n: usize, weights: Vec<f32>, propagator: Vec<Vec<Vec<usize>>>) -> Result<Self, WfcError> {
+        if width == 0 || height == 0 {
+            return Err(WfcError::InvalidParameter(format!("The grid must have at least one cell, got {width}x{height}")));
+        }
+        let t = weights.len();
+        let words = t.div_ceil(64);
+        let weight_log_weights: Vec<f32> = weights.iter().map(|&w| w * w.ln()).collect();
+        let sum_of_weights: f32 = weights.iter().sum();
+        let sum_of_weight_log_weights: f32 = weight_log_weights.iter().sum();
+        // A tile starts with as many compatible neighbors in direction d as the tiles that accept it from there
+        let initial_compatible: Vec<i32> = (0..t)
+            .flat_map(|t| (0..4).map(move |d| (t, d)))
+            .map(|(t, d)| propagator[Self::OPPOSITE[d]][t].len() as i32)
+            .collect();
+
+        let state = State {
+            wave: vec![0; width * height * words],
+            words,
+            observed: vec![None; width * height],
+            compatible: vec![0; width * height * t * 4],
+            initial_compatible,
+            stack: Vec::new(),
+            presets: vec![None; width * height],
+            active: None,
+            backtrack_budget: None,
+            backtracks: 0,
+            journal: Vec::new(),
+            decisions: Vec::new(),
+            m_x: width,
+            m_y: height,
+            t,
+            n,
+            periodic_x: false,
+            periodic_y: false,
+            ground: false,
+            boundary: [None, None, None, None],
+            weights,
+            weight_log_weights,
+            cell_weights: None,
+            cell_weight_log_weights: Vec::new(),
+            distribution: vec![0f32; t],
+            sums_of_ones: vec![0; width * height],
+            sum_of_weights,
+            sum_of_weight_log_weights,
+            starting_entropy: sum_of_weights.ln() - (sum_of_weight_log_weights / sum_of_weights),
+            sums_of_weights: vec![0f32; width * height],
+            sums_of_weight_log_weights: vec![0f32; width * height],
+            entropies: vec![0f32; width * height],
+            noise: vec![0f32; width * height],
+            heuristic: Heuristic::default(),
+            queue: BinaryHeap::new(),
+            order: Vec::new(),
+            cursor: 0,
+        };
+
+        Ok(Model { propagator, state, constraints: Vec::new() })
+    }
+
+    /// Runs the algorithm for at most `limit` observations (-1 means until the wave has collapsed).
+    /// Fails with [`WfcError::Contradiction`] if a contradiction was reached (and, with backtracking, couldn't be undone).
+    pub fn run(&mut self, limit: isize, seed: [u8; 32]) -> Result<(), WfcError> {
+        self.state.clear();
+        if !self.init_constraints() {
+            return Err(WfcError::Contradiction);
+        }
+        self.state.apply_presets();
+        if !self.propagate() {
+            return Err(WfcError::Contradiction);
+        }
+        // Whatever follows from the initial state can't be undone
+        self.state.journal.clear();
+
+        let mut rng = StdRng::from_seed(seed);
+        // Fixed for the whole run, so that the order of the queue only depends on the seed
+        for noise in self.state.noise.iter_mut() {
+            *noise = rng.random::<f32>();
+        }
+        self.state.fill_queue();
+        self.state.fill_order(&mut rng);
+        let mut l = 0;
+        loop {
+            if limit >= 0 && l >= limit {
+                // Stopped early: only the cells left with a single tile count as observed
+                let state = &mut self.state;
+                for i in 0..state.observed.len() {
+                    if state.sums_of_ones[i] == 1 && state.is_active(i) {
+                        state.observed[i] = (0..state.t).find(|&t| state.is_allowed(i, t));
+                    }
+                }
+                break;
+            }
+
+            if let Some(node) = self.state.next_unobserved_node() {
+                let tile = self.state.observe(node, &mut rng);
+                let mut success = self.propagate() && self.notify_observe(node, tile);
+                while !success {
+                    if !self.state.backtrack(&mut self.constraints) {
+                        return Err(WfcError::Contradiction);
+                    }
+                    success = self.propagate();
+                }
+            } else {
+                let state = &mut self.state;
+                for i in 0..state.observed.len() {
+                    for t in 0..state.t {
+                        if state.is_active(i) && state.is_allowed(i, t) {
+                            state.observed[i] = Some(t);
+                        }
+                    }
+                }
+                return Ok(());
+            }
+            l += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the algorithm with `seed`, then up to `retries` more times with seeds derived from it,
+    /// until one of the runs succeeds. Returns the attempt that succeeded (0 is the first one) and the
+    /// seed it used, which reproduces the same result when passed to [`seed_bytes`] and [`run`](Self::run).
+    pub fn run_with_retries(&mut self, limit: isize, seed: u64, retries: usize) -> Result<(usize, u64), WfcError> {
+        for attempt in 0..=retries {
+            let attempt_seed = if attempt == 0 { seed } else { derive_seed(seed, attempt as u64) };
+            match self.run(limit, seed_bytes(attempt_seed)) {
+                Ok(()) => return Ok((attempt, attempt_seed)),
+                Err(WfcError::Contradiction) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(WfcError::Contradiction)
+    }
+
+    fn propagate(&mut self) -> bool {
+        let Model { propagator, state, constraints } = self;
+
+        while let Some((position, tile)) = state.stack.pop() {
+            // Every constraint sees the ban, even after one of them rejected it
+            let mut accepted = true;
+            for constraint in constraints.iter_mut() {
+                accepted &= constraint.on_ban(&mut Wave { state }, position, tile);
+            }
+            if !accepted || state.sums_of_ones[position] == 0 {
+                Self::drain(state, constraints);
+                return false;
+            }
+
+            let position_x = position % state.m_x;
+            let position_y = position / state.m_x;
+
+            for (d, direction) in propagator.iter().enumerate() {
+                let Some(position_x_move) = Self::move_along(position_x, Self::DX[d], state.m_x, state.n, state.periodic_x) else {
+                    continue;
+                };
+                let Some(position_y_move) = Self::move_along(position_y, Self::DY[d], state.m_y, state.n, state.periodic_y) else {
+                    continue;
+                };
+
+                let position_move = position_x_move + position_y_move * state.m_x;
+                // Inactive cells are outside of the grid
+                if !state.is_active(position_move) {
+                    continue;
+                }
+
+                for &neighbor_tile in &direction[tile] {
+                    if state.backtrack_budget.is_some() {
+                        state.journal.push(Change::Decrement { i: position_move, t: neighbor_tile, d });
+                    }
+                    let c = (position_move * state.t + neighbor_tile) * 4 + d;
+                    state.compatible[c] -= 1;
+                    if state.compatible[c] == 0 {
+                        state.ban(position_move, neighbor_tile);
+                    }
+                }
+            }
+        }
+        state.sums_of_ones[0] > 0
+    }
+
+    fn init_constraints(&mut self) -> bool {
+        let Model { state, constraints, .. } = self;
+        // Every constraint is initialized, even after one of them failed, before the stack is drained
+        let mut accepted = true;
+        for constraint in constraints.iter_mut() {
+            accepted &= constraint.init(&mut Wave { state });
+        }
+        if accepted {
+            return true;
+        }
+        Self::drain(state, constraints);
+        false
+    }
+
+    /// Lets the constraints react to the observation of `tile` in `node`, then propagates what they banned
+    fn notify_observe(&mut self, node: usize, tile: usize) -> bool {
+        let Model { state, constraints, .. } = self;
+        // Every constraint sees the observation, even after one of them rejected it
+        let mut accepted = true;
+        for constraint in constraints.iter_mut() {
+            accepted &= constraint.on_observe(&mut Wave { state }, node, tile);
+        }
+        if !accepted {
+            Self::drain(state, constraints);
+            return false;
+        }
+        self.propagate()
+    }
+
+    /// Empties the stack after a contradiction, still showing its bans to the constraints so that
+    /// their bookkeeping stays consistent when the bans are undone
+    fn drain(state: &mut State, constraints: &mut [Box<dyn Constraint>]) {
+        while let Some((i, t)) = state.stack.pop() {
+            for constraint in constraints.iter_mut() {
+                constraint.on_ban(&mut Wave { state }, i, t);
+            }
+        }
+    }
+
+    /// Coordinate reached moving by `delta` on an axis of length `size`: wraps around
+    /// when the axis is periodic, otherwise `None` once the pattern would leave the grid
+    fn move_along(coordinate: usize, delta: isize, size: usize, n: usize, periodic: bool) -> Option<usize> {
+        let moved = coordinate as isize + delta;
+        if periodic {
+            Some(moved.rem_euclid(size as isize) as usize)
+        } else if moved < 0 || moved + n as isize > size as isize {
+            None
+        } else {
+            Some(moved as usize)
+        }
+    }
+
+    /// Makes the output wrap around horizontally, vertically or both (a torus), so that
+    /// it tiles seamlessly along those axes
+    pub fn set_periodic(&mut self, periodic_x: bool, periodic_y: bool) {
+        self.state.periodic_x = periodic_x;
+        self.state.periodic_y = periodic_y;
+    }
+
+    /// Enables backtracking: on a contradiction the last observations are taken back, at most
+    /// `budget` times per run, instead of giving up. `None` disables it.
+    pub fn set_backtracking(&mut self, budget: Option<usize>) {
+        self.state.backtrack_budget = budget;
+    }
+
+    /// Chooses how the next cell to observe is picked, [`Heuristic::Entropy`] by default
+    pub fn set_heuristic(&mut self, heuristic: Heuristic) {
+        self.state.heuristic = heuristic;
+    }
+
+    /// Adds a rule checked during every following run, see [`Constraint`]
+    pub fn add_constraint<C: Constraint + 'static>(&mut self, constraint: C) {
+        self.constraints.push(Box::new(constraint));
+    }
+
+    /// Removes every constraint added with [`add_constraint`](Self::add_constraint)
+    pub fn clear_constraints(&mut self) {
+        self.constraints.clear();
+    }
+
+    /// Restricts the cell at (`x`, `y`) to the tiles in `allowed` for every following run: the other
+    /// tiles are banned, and the consequences propagated, before the first observation.
+    /// Presetting a cell again keeps only the tiles allowed by both presets. Inactive cells can't be
+    /// preset, see [`set_active`](Self::set_active).
+    pub fn preset(&mut self, x: usize, y: usize, allowed: &[usize]) -> Result<(), WfcError> {
+        if x >= self.state.m_x || y >= self.state.m_y {
+            return Err(WfcError::InvalidParameter(format!("Cell ({x}, {y}) is outside of the {}x{} grid", self.state.m_x, self.state.m_y)));
+        }
+        if let Some(t) = allowed.iter().find(|&&t| t >= self.state.t) {
+            return Err(WfcError::InvalidParameter(format!("Tile {t} doesn't exist, there are {} tiles", self.state.t)));
+        }
+        if !self.state.is_active(x + y * self.state.m_x) {
+            return Err(WfcError::InvalidParameter(format!("Cell ({x}, {y}) is inactive and can't be preset")));
+        }
+
+        let preset = self.state.presets[x + y * self.state.m_x].get_or_insert_with(|| vec![true; self.state.t]);
+        for (t, keep) in preset.iter_mut().enumerate() {
+            *keep &= allowed.contains(&t);
+        }
+        Ok(())
+    }
+
+    /// Removes every preset, see [`preset`](Self::preset)
+    pub fn clear_presets(&mut self) {
+        self.state.presets.fill(None);
+    }
+
+    /// Gives every cell its own tile weights for the following runs, `tile_count` of them per cell in
+    /// row-major order (at `i * tile_count + t`): the observation of a cell draws its tile from its own
+    /// weights, and its entropy is computed from them. `None` goes back to the weights of the tiles.
+    pub fn set_cell_weights(&mut self, weights: Option<Vec<f32>>) -> Result<(), WfcError> {
+        let expected = self.state.observed.len() * self.state.t;
+        if let Some(weights) = &weights {
+            if weights.len() != expected {
+                return Err(WfcError::InvalidParameter(format!("Expected {expected} cell weights ({} tiles for each of the {} cells), got {}", self.state.t, self.state.observed.len(), weights.len())));
+            }
+            if let Some(weight) = weights.iter().find(|w| !(w.is_finite() && **w > 0.0)) {
+                return Err(WfcError::InvalidParameter(format!("Cell weights must be positive, got {weight}")));
+            }
+        }
+
+        self.state.cell_weight_log_weights = weights.iter().flatten().map(|&w| w * w.ln()).collect();
+        self.state.cell_weights = weights;
+        Ok(())
+    }
+
+    /// Treats the cells just outside `side` of the grid as holding one of `tiles` in every following
+    /// run, so that the cells along it only get tiles that can sit next to them. `None` leaves the side
+    /// open again, and sides along a periodic axis have no outside.
+    pub fn set_boundary(&mut self, side: Side, tiles: Option<&[usize]>) -> Result<(), WfcError> {
+        let d = side.direction();
+        let Some(tiles) = tiles else {
+            self.state.boundary[d] = None;
+            return Ok(());
+        };
+        if let Some(t) = tiles.iter().find(|&&t| t >= self.state.t) {
+            return Err(WfcError::InvalidParameter(format!("Tile {t} doesn't exist, there are {} tiles", self.state.t)));
+        }
+
+        let mut allowed = vec![false; self.state.t];
+        for &outside in tiles {
+            for &t in &self.propagator[Self::OPPOSITE[d]][outside] {
+                allowed[t] = true;
+            }
+        }
+        self.state.boundary[d] = Some(allowed);
+        Ok(())
+    }
+
+    /// Restricts the generation to the cells flagged in `active`, one flag per cell in row-major order,
+    /// for the following runs; `None` makes every cell active again. The other cells are never observed
+    /// and keep every tile, and the propagation treats them as outside of the grid: the cells next to
+    /// them are only restricted by the tiles set with [`set_boundary`](Self::set_boundary) for that
+    /// direction, if any. Only models whose patterns are a single cell support it, and preset cells
+    /// can't be made inactive.
+    pub fn set_active(&mut self, active: Option<Vec<bool>>) -> Result<(), WfcError> {
+        if let Some(active) = &active {
+            if self.state.n != 1 {
+                return Err(WfcError::InvalidParameter(format!("Active cells need patterns of a single cell, the patterns are {}x{}", self.state.n, self.state.n)));
+            }
+            if active.len() != self.state.observed.len() {
+                return Err(WfcError::InvalidParameter(format!("Expected {} active flags for the {}x{} grid, got {}", self.state.observed.len(), self.state.m_x, self.state.m_y, active.len())));
+            }
+            if let Some(i) = (0..active.len()).find(|&i| !active[i] && self.state.presets[i].is_some()) {
+                return Err(WfcError::InvalidParameter(format!("Cell ({}, {}) is preset and can't be made inactive", i % self.state.m_x, i / self.state.m_x)));
+            }
+        }
+        self.state.active = active;
+        Ok(())
+    }
+
+    pub(crate) fn set_ground(&mut self, ground: bool) {
+        self.state.ground = ground;
+    }
+
+    /* Accessors */
+
+    /// Width of the grid, in cells
+    pub fn width(&self) -> usize {
+        self.state.m_x
+    }
+
+    /// Height of the grid, in cells
+    pub fn height(&self) -> usize {
+        self.state.m_y
+    }
+
+    /// Number of tiles (or patterns) the wave chooses from
+    pub fn tile_count(&self) -> usize {
+        self.state.t
+    }
+
+    /// Whether tile `t` is still allowed in cell `i`
+    pub fn is_allowed(&self, i: usize, t: usize) -> bool {
+        self.state.is_allowed(i, t)
+    }
+
+    /// Weight of each tile
+    pub fn weights(&self) -> &[f32] {
+        &self.state.weights
+    }
+
+    /// Weight of tile `t` in cell `i`, as set by [`set_cell_weights`](Self::set_cell_weights)
+    pub fn weight_at(&self, i: usize, t: usize) -> f32 {
+        self.state.weight(i, t)
+    }
+
+    /// Whether the output wraps around horizontally and vertically
+    pub fn periodic(&self) -> (bool, bool) {
+        (self.state.periodic_x, self.state.periodic_y)
+    }
+
+    /// Whether cell `i` takes part in the generation, see [`set_active`](Self::set_active)
+    pub fn is_active(&self, i: usize) -> bool {
+        self.state.is_active(i)
+    }
+
+    /// Tiles that can be placed next to `tile` in `direction` (0 left, 1 below, 2 right, 3 above)
+    pub fn neighbors(&self, tile: usize, direction: usize) -> &[usize] {
+        &self.propagator[direction][tile]
+    }
+
+    /// Number of observations taken back during the last run
+    pub fn backtracks(&self) -> usize {
+        self.state.backtracks
+    }
+
+    /// Observed tile index for every cell, in row-major order. `None` means the cell has not been observed yet
+    pub fn observed(&self) -> &[Option<usize>] {
+        &self.state.observed
+    }
+
+    /// Observed tile index of the cell at (`x`, `y`)
+    pub fn observed_at(&self, x: usize, y: usize) -> Option<usize> {
+        self.state.observed[x + y * self.state.m_x]
+    }
+
+    pub(crate) const DX: [isize; 4] = [-1, 0, 1, 0];
+    pub(crate) const DY: [isize; 4] = [0, 1, 0, -1];
+    const OPPOSITE: [usize; 4] = [2, 3, 0, 1];
+}
+
+impl State {
+
+    /// Next cell to observe according to the heuristic, `None` once every cell has been observed
+    fn next_unobserved_node(&mut self) -> Option<usize> {
+        if !self.uses_queue() {
+            while let Some(&node) = self.order.get(self.cursor) {
+                if self.sums_of_ones[node] > 1 {
+                    return Some(node);
+                }
+                self.cursor += 1;
+            }
+            return None;
+        }
+
+        while let Some(Candidate { priority, node, .. }) = self.queue.pop() {
+            if self.sums_of_ones[node] > 1 && priority == self.priority(node) {
+                return Some(node);
+            }
+        }
+        None
+    }
+
+    /// Whether the heuristic picks cells from the queue, rather than following a fixed order
+    fn uses_queue(&self) -> bool {
+        matches!(self.heuristic, Heuristic::Entropy | Heuristic::Mrv)
+    }
+
+    /// Key of cell `i` in the queue, lowest first
+    fn priority(&self, i: usize) -> f32 {
+        match self.heuristic {
+            Heuristic::Mrv => self.sums_of_ones[i] as f32,
+            _ => self.entropies[i],
+        }
+    }
+
+    /// Whether the pattern of cell `i` fits the grid: inactive cells and cells closer than `n` to the
+    /// right or bottom border are never observed, unless the grid wraps around there
+    fn observable(&self, i: usize) -> bool {
+        self.is_active(i) &&
+        (self.periodic_x || i % self.m_x + self.n <= self.m_x) &&
+        (self.periodic_y || i / self.m_x + self.n <= self.m_y)
+    }
+
+    /// Rebuilds the queue with one up to date entry per cell left to observe
+    fn fill_queue(&mut self) {
+        let candidates: Vec<Candidate> = (0..self.observed.len())
+            .filter(|&i| self.uses_queue() && self.observable(i) && self.sums_of_ones[i] > 1)
+            .map(|i| Candidate { priority: self.priority(i), noise: self.noise[i], node: i })
+            .collect();
+        self.queue = BinaryHeap::from(candidates);
+    }
+
+    /// Visiting order of the heuristics that don't use the queue, drawn at the start of every run
+    fn fill_order(&mut self, rng: &mut StdRng) {
+        let mut order: Vec<usize> = (0..self.observed.len()).filter(|&i| self.observable(i)).collect();
+
+        match self.heuristic {
+            Heuristic::Entropy | Heuristic::Mrv => order.clear(),
+            Heuristic::Scanline => {}
+            Heuristic::Spiral => {
+                let (center_x, center_y) = ((self.m_x as f32 - 1.0) / 2.0, (self.m_y as f32 - 1.0) / 2.0);
+                let ring_and_angle = |i: usize| {
+                    let (dx, dy) = ((i % self.m_x) as f32 - center_x, (i / self.m_x) as f32 - center_y);
+                    (dx.abs().max(dy.abs()), dy.atan2(dx))
+                };
+                order.sort_by(|&a, &b| {
+                    let ((ring_a, angle_a), (ring_b, angle_b)) = (ring_and_angle(a), ring_and_angle(b));
+                    ring_a.total_cmp(&ring_b).then(angle_a.total_cmp(&angle_b))
+                });
+            }
+            Heuristic::Random => order.shuffle(rng),
+        }
+
+        self.order = order;
+        self.cursor = 0;
+    }
+
+    /// Picks one of the tiles allowed in `node` and bans the others, returns the chosen tile
+    fn observe(&mut self, node: usize, rng: &mut StdRng) -> usize {
+       for t in 0..self.t {
+            self.distribution[t] = match self.is_allowed(node, t) {
+                true => self.weight(node, t),
+                false => 0f32,
+            };
+       }
+
+       let r = array_utils::weighted_random(&self.distribution, rng.random::<f32>());
+       if self.backtrack_budget.is_some() {
+            self.decisions.push(Decision { journal_len: self.journal.len(), node, tile: r });
+       }
+       for t in 0..self.t {
+            if self.is_allowed(node, t) != (t == r) {
+                self.ban(node, t);
+            }
+       }
+       r
+    }
+
+    fn ban(&mut self, i: usize, t: usize){
+        if self.backtrack_budget.is_some() {
+            let mut compatible = [0; 4];
+            compatible.copy_from_slice(&self.compatible[(i * self.t + t) * 4..][..4]);
+            self.journal.push(Change::Ban { i, t, compatible });
+        }
+
+        self.wave[i * self.words + t / 64] &= !(1 << (t % 64));
+
+        self.compatible[(i * self.t + t) * 4..][..4].fill(0);
+
+        self.stack.push((i, t));
+
+        self.sums_of_ones[i] -= 1;
+        self.sums_of_weights[i] -= self.weight(i, t);
+        self.sums_of_weight_log_weights[i] -= self.weight_log_weight(i, t);
+
+        self.update_entropy(i);
+    }
+
+    fn update_entropy(&mut self, i: usize) {
+        if self.sums_of_weights[i] > 0.0 {
+            self.entropies[i] = self.sums_of_weights[i].ln() - self.sums_of_weight_log_weights[i] / self.sums_of_weights[i];
+        } else {
+            self.entropies[i] = f32::NEG_INFINITY;
+        }
+
+        if self.sums_of_ones[i] > 1 && self.uses_queue() && self.observable(i) {
+            self.queue.push(Candidate { priority: self.priority(i), noise: self.noise[i], node: i });
+            // Stale entries pile up with every ban, drop them once they outnumber the cells
+            if self.queue.len() > 4 * self.observed.len() {
+                self.fill_queue();
+            }
+        }
+    }
+
+    /// Takes back the last observation, then bans the tile it chose so that the next attempt picks another one.
+    /// Returns `false` when there's nothing left to take back or the budget is exhausted.
+    fn backtrack(&mut self, constraints: &mut [Box<dyn Constraint>]) -> bool {
+        if self.backtrack_budget.is_none_or(|budget| self.backtracks >= budget) {
+            return false;
+        }
+        let Some(decision) = self.decisions.pop() else {
+            return false;
+        };
+
+        self.undo(decision.journal_len, constraints);
+        // Cells before the cursor may be open again
+        self.cursor = 0;
+        self.backtracks += 1;
+        self.ban(decision.node, decision.tile);
+        true
+    }
+
+    /// Reverts the journal, most recent change first, until it's `journal_len` long
+    fn undo(&mut self, journal_len: usize, constraints: &mut [Box<dyn Constraint>]) {
+        while self.journal.len() > journal_len {
+            match self.journal.pop() {
+                Some(Change::Decrement { i, t, d }) => self.compatible[(i * self.t + t) * 4 + d] += 1,
+                Some(Change::Ban { i, t, compatible }) => {
+                    self.wave[i * self.words + t / 64] |= 1 << (t % 64);
+                    self.compatible[(i * self.t + t) * 4..][..4].copy_from_slice(&compatible);
+
+                    self.sums_of_ones[i] += 1;
+                    self.sums_of_weights[i] += self.weight(i, t);
+                    self.sums_of_weight_log_weights[i] += self.weight_log_weight(i, t);
+
+                    self.update_entropy(i);
+
+                    for constraint in constraints.iter_mut() {
+                        constraint.on_unban(&Wave { state: self }, i, t);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn clear(&mut self){
+        // Every tile allowed: all bits set, except the ones past the last tile
+        let full_cell: Vec<u64> = (0..self.words)
+            .map(|w| match self.t - w * 64 {
+                remaining if remaining >= 64 => u64::MAX,
+                remaining => (1 << remaining) - 1,
+            })
+            .collect();
+
+        for i in 0..self.observed.len() {
+            self.wave[i * self.words..][..self.words].copy_from_slice(&full_cell);
+            self.compatible[i * self.t * 4..][..self.t * 4].copy_from_slice(&self.initial_compatible);
+
+            self.sums_of_ones[i] = self.t;
+            match &self.cell_weights {
+                Some(cell_weights) => {
+                    let sum_of_weights: f32 = cell_weights[i * self.t..][..self.t].iter().sum();
+                    let sum_of_weight_log_weights: f32 = self.cell_weight_log_weights[i * self.t..][..self.t].iter().sum();
+                    self.sums_of_weights[i] = sum_of_weights;
+                    self.sums_of_weight_log_weights[i] = sum_of_weight_log_weights;
+                    self.entropies[i] = sum_of_weights.ln() - sum_of_weight_log_weights / sum_of_weights;
+                }
+                None => {
+                    self.sums_of_weights[i] = self.sum_of_weights;
+                    self.sums_of_weight_log_weights[i] = self.sum_of_weight_log_weights;
+                    self.entropies[i] = self.starting_entropy;
+                }
+            }
+        }
+
+        for i in 0..self.observed.len() {
+            self.observed[i] = None;
+        }
+
+        self.backtracks = 0;
+        self.journal.clear();
+        self.decisions.clear();
+    }
+
+    /// Bans what the ground, the boundary and the presets exclude, leaving inactive cells alone
+    fn apply_presets(&mut self) {
+        // The last tile is the ground: it fills the bottom row and can't appear anywhere else
+        if self.ground {
+            for x in 0..self.m_x {
+                for t in 0..self.t - 1 {
+                    if self.is_allowed(x + (self.m_y - 1) * self.m_x, t) {
+                        self.ban(x + (self.m_y - 1) * self.m_x, t);
+                    }
+                }
+                for y in 0..self.m_y - 1 {
+                    if self.is_allowed(x + y * self.m_x, self.t - 1) {
+                        self.ban(x + y * self.m_x, self.t - 1);
+                    }
+                }
+            }
+        }
+
+        // Cells next to an inactive one are on the border of the area
+        if let Some(active) = &self.active {
+            let mut border = Vec::new();
+            for i in (0..self.observed.len()).filter(|&i| active[i]) {
+                for d in (0..4).filter(|&d| self.boundary[d].is_some()) {
+                    let x = Model::move_along(i % self.m_x, Model::DX[d], self.m_x, self.n, self.periodic_x);
+                    let y = Model::move_along(i / self.m_x, Model::DY[d], self.m_y, self.n, self.periodic_y);
+                    if let (Some(x), Some(y)) = (x, y) && !active[x + y * self.m_x] {
+                        border.push((i, d));
+                    }
+                }
+            }
+            for (i, d) in border {
+                for t in 0..self.t {
+                    if self.is_allowed(i, t) && self.boundary[d].as_ref().is_some_and(|allowed| !allowed[t]) {
+                        self.ban(i, t);
+                    }
+                }
+            }
+        }
+
+        for side in Side::ALL {
+            let d = side.direction();
+            if self.boundary[d].is_none() || [self.periodic_x, self.periodic_y][d % 2] {
+                continue;
+            }
+            // The last cells where a pattern fits, for the right and bottom sides
+            let border: Vec<usize> = match side {
+                Side::Left => (0..self.m_y).map(|y| y * self.m_x).collect(),
+                Side::Right => (0..self.m_y).map(|y| self.m_x - self.n + y * self.m_x).collect(),
+                Side::Top => (0..self.m_x).collect(),
+                Side::Bottom => (0..self.m_x).map(|x| x + (self.m_y - self.n) * self.m_x).collect(),
+            };
+            for i in border {
+                for t in 0..self.t {
+                    if self.is_active(i) && self.is_allowed(i, t) && self.boundary[d].as_ref().is_some_and(|allowed| !allowed[t]) {
+                        self.ban(i, t);
+                    }
+                }
+            }
+        }
+
+        for i in 0..self.observed.len() {
+            for t in 0..self.t {
+                if self.is_active(i) && self.is_allowed(i, t) && self.presets[i].as_ref().is_some_and(|allowed| !allowed[t]) {
+                    self.ban(i, t);
+                }
+            }
+        }
+    }
+
+    /// Weight of tile `t` in cell `i`
+    fn weight(&self, i: usize, t: usize) -> f32 {
+        match &self.cell_weights {
+            Some(cell_weights) => cell_weights[i * self.t + t],
+            None => self.weights[t],
+        }
+    }
+
+    fn weight_log_weight(&self, i: usize, t: usize) -> f32 {
+        match &self.cell_weights {
+            Some(_) => self.cell_weight_log_weights[i * self.t + t],
+            None => self.weight_log_weights[t],
+        }
+    }
+
+    fn is_active(&self, i: usize) -> bool {
+        self.active.as_ref().is_none_or(|active| active[i])
+    }
+
+    fn is_allowed(&self, i: usize, t: usize) -> bool {
+        self.wave[i * self.words + t / 64] >> (t % 64) & 1 == 1
+    }
+}
+
+impl Wave<'_> {
+
+    /// Width of the grid, in cells
+    pub fn width(&self) -> usize {
+        self.state.m_x
+    }
+
+    /// Height of the grid, in cells
+    pub fn height(&self) -> usize {
+        self.state.m_y
+    }
+
+    /// Number of tiles (or patterns) the wave chooses from
+    pub fn tile_count(&self) -> usize {
+        self.state.t
+    }
+
+    /// Whether tile `t` is still allowed in cell `i`
+    pub fn is_allowed(&self, i: usize, t: usize) -> bool {
+        self.state.is_allowed(i, t)
+    }
+
+    /// Whether the grid wraps around horizontally and vertically
+    pub fn periodic(&self) -> (bool, bool) {
+        (self.state.periodic_x, self.state.periodic_y)
+    }
+
+    /// Number of tiles still allowed in cell `i`
+    pub fn remaining(&self, i: usize) -> usize {
+        self.state.sums_of_ones[i]
+    }
+
+    /// Whether cell `i` takes part in the generation: inactive cells keep every tile and are
+    /// never observed, see [`Model::set_active`]
+    pub fn is_active(&self, i: usize) -> bool {
+        self.state.is_active(i)
+    }
+
+    /// Bans tile `t` from cell `i`, if it's still allowed there and the cell is active. A cell left
+    /// without tiles is reported as a contradiction by the propagation that follows
+    pub fn ban(&mut self, i: usize, t: usize) {
+        if self.state.is_active(i) && self.state.is_allowed(i, t) {
+            self.state.ban(i, t);
+        }
+    }
+}
+
+/// Expands a numeric seed into the seed taken by [`Model::run`]
+pub fn seed_bytes(seed: u64) -> [u8; 32] {
+    let mut arr = [0u8; 32];
+    arr[..8].copy_from_slice(&seed.to_le_bytes());
+    arr
+}
+
+/// Deterministically derives a new seed from `seed` and `index` (SplitMix64)
+pub fn derive_seed(seed: u64, index: u64) -> u64 {
+    let mut z = seed.wrapping_add(index.wrapping_mul(0x9E3779B97F4A7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Steps of a run, for the tests of the constraints to drive the model by hand
+#[cfg(test)]
+impl Model {
+    /// Gets the wave ready like [`run`](Self::run) does before the first observation, with backtracking on
+    pub(crate) fn start(&mut self) -> bool {
+        self.state.backtrack_budget.get_or_insert(0);
+        self.state.clear();
+        if !self.init_constraints() {
+            return false;
+        }
+        self.state.apply_presets();
+        let success = self.propagate();
+        self.state.journal.clear();
+        self.state.fill_queue();
+        success
+    }
+
+    /// Observes `tile` in `node` and propagates, returns `false` on contradiction
+    pub(crate) fn decide(&mut self, node: usize, tile: usize) -> bool {
+        self.state.decisions.push(Decision { journal_len: self.state.journal.len(), node, tile });
+        for t in 0..self.state.t {
+            if t != tile && self.state.is_allowed(node, t) {
+                self.state.ban(node, t);
+            }
+        }
+        self.propagate() && self.notify_observe(node, tile)
+    }
+
+    /// Takes back the last decision, without banning its tile
+    pub(crate) fn take_back(&mut self) {
+        let decision = self.state.decisions.pop().expect("nothing to take back");
+        self.state.undo(decision.journal_len, &mut self.constraints);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    /// A `width` x `height` wave of three tiles, each one allowed next to itself and to the tiles
+    /// next to it in the list, in the state `run` starts observing from
+    fn model(width: usize, height: usize) -> Model {
+        model_with(width, height, Vec::new())
+    }
+
+    /// Same as [`model`], with `constraints`
+    fn model_with(width: usize, height: usize, constraints: Vec<Box<dyn Constraint>>) -> Model {
+        let neighbors = vec![vec![0, 1], vec![0, 1, 2], vec![1, 2]];
+        let mut model = Model::new(width, height, 1, vec![1.0, 2.0, 0.5], vec![neighbors; 4]).unwrap();
+        model.constraints = constraints;
+        assert!(model.start());
+        model
+    }
+
+    /// Keeps its own count of the tiles allowed in each cell, through the hooks
+    struct Remaining(Rc<RefCell<Vec<usize>>>);
+
+    impl Constraint for Remaining {
+        fn init(&mut self, wave: &mut Wave) -> bool {
+            *self.0.borrow_mut() = (0..wave.width() * wave.height()).map(|i| wave.remaining(i)).collect();
+            true
+        }
+
+        fn on_ban(&mut self, _wave: &mut Wave, i: usize, _t: usize) -> bool {
+            self.0.borrow_mut()[i] -= 1;
+            true
+        }
+
+        fn on_unban(&mut self, _wave: &Wave, i: usize, _t: usize) {
+            self.0.borrow_mut()[i] += 1;
+        }
+    }
+
+    /// Everything taking back an observation has to restore
+    struct Snapshot {
+        wave: Vec<u64>,
+        compatible: Vec<i32>,
+        sums_of_ones: Vec<usize>,
+        sums: Vec<(f32, f32, f32)>,
+    }
+
+    impl Snapshot {
+        fn of(model: &Model) -> Self {
+            let state = &model.state;
+            Snapshot {
+                wave: state.wave.clone(),
+                compatible: state.compatible.clone(),
+                sums_of_ones: state.sums_of_ones.clone(),
+                sums: (0..state.observed.len())
+                    .map(|i| (state.sums_of_weights[i], state.sums_of_weight_log_weights[i], state.entropies[i]))
+                    .collect(),
+            }
+        }
+
+        fn assert_restored(&self, model: &Model) {
+            let now = Snapshot::of(model);
+            assert_eq!(now.wave, self.wave);
+            assert_eq!(now.compatible, self.compatible);
+            assert_eq!(now.sums_of_ones, self.sums_of_ones);
+            // The sums are restored by adding back what was subtracted, up to rounding
+            for (now, before) in now.sums.iter().zip(&self.sums) {
+                assert!((now.0 - before.0).abs() < 1e-5 && (now.1 - before.1).abs() < 1e-5 && (now.2 - before.2).abs() < 1e-5);
+            }
+        }
+    }
+
+    /// Observes the next cell and propagates, returns the cell and the tile chosen there
+    fn decide(model: &mut Model, rng: &mut StdRng) -> (usize, usize) {
+        let node = model.state.next_unobserved_node().unwrap();
+        model.state.observe(node, rng);
+        assert!(model.propagate());
+        let decision = model.state.decisions.last().unwrap();
+        (decision.node, decision.tile)
+    }
+
+    #[test]
+    fn undo_restores_the_state_before_the_observations() {
+        let mut model = model(5, 4);
+        model.set_backtracking(Some(10));
+        let before = Snapshot::of(&model);
+        let mut rng = StdRng::from_seed([1; 32]);
+
+        for _ in 0..3 {
+            decide(&mut model, &mut rng);
+        }
+        assert_ne!(Snapshot::of(&model).wave, before.wave);
+
+        model.state.undo(0, &mut model.constraints);
+        before.assert_restored(&model);
+    }
+
+    #[test]
+    fn undo_gives_the_tiles_back_to_the_constraints() {
+        let remaining = Rc::new(RefCell::new(Vec::new()));
+        let mut model = model_with(5, 4, vec![Box::new(Remaining(remaining.clone()))]);
+        model.set_backtracking(Some(10));
+        let before = remaining.borrow().clone();
+        let mut rng = StdRng::from_seed([4; 32]);
+
+        for _ in 0..3 {
+            decide(&mut model, &mut rng);
+        }
+        assert_eq!(*remaining.borrow(), model.state.sums_of_ones);
+
+        model.state.undo(0, &mut model.constraints);
+        assert_eq!(*remaining.borrow(), before);
+        assert_eq!(*remaining.borrow(), model.state.sums_of_ones);
+    }
+
+    #[test]
+    fn backtrack_takes_back_the_last_observation_and_bans_its_tile() {
+        let mut model = model(5, 4);
+        model.set_backtracking(Some(1));
+        let mut rng = StdRng::from_seed([2; 32]);
+        decide(&mut model, &mut rng);
+        let before = Snapshot::of(&model);
+        let journal_len = model.state.journal.len();
+
+        let (node, tile) = decide(&mut model, &mut rng);
+        assert!(model.state.backtrack(&mut model.constraints));
+        assert_eq!(model.backtracks(), 1);
+        assert!(!model.is_allowed(node, tile));
+
+        // Without the ban of the chosen tile, the state is the one before the observation
+        model.state.stack.clear();
+        model.state.undo(journal_len, &mut model.constraints);
+        before.assert_restored(&model);
+
+        // The budget is spent
+        decide(&mut model, &mut rng);
+        assert!(!model.state.backtrack(&mut model.constraints));
+    }
+
+    #[test]
+    fn backtrack_fails_without_budget() {
+        let mut model = model(5, 4);
+        model.set_backtracking(Some(0));
+        let mut rng = StdRng::from_seed([3; 32]);
+        let journal_len = model.state.journal.len();
+        decide(&mut model, &mut rng);
+
+        assert!(!model.state.backtrack(&mut model.constraints));
+        assert_eq!(model.backtracks(), 0);
+        assert_eq!(model.state.decisions.len(), 1);
+        assert!(model.state.journal.len() > journal_len);
+    }
+
+    #[test]
+    fn inactive_cells_cant_be_preset() {
+        let mut model = model(3, 3);
+        let mut active = vec![true; 9];
+        active[4] = false;
+
+        model.preset(1, 1, &[0]).unwrap();
+        assert!(model.set_active(Some(active.clone())).is_err());
+        model.clear_presets();
+        model.set_active(Some(active)).unwrap();
+        assert!(model.preset(1, 1, &[0]).is_err());
+        model.preset(0, 0, &[0]).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::{array_utils::{reflect, rotate, tile}, bitmap_utils, error::WfcError, model::{Generator, Model}};
+
+/// Model that learns the NxN patterns of a sample image and generates bitmaps where
+/// every NxN window is one of those patterns. Each cell of the wave is one pixel of the output.
+pub struct OverlappingModel {
+    model: Model,
+
+    patterns: Vec<Vec<u32>>,
+    colors: Vec<u32>,
+    n: usize,
+}
+
+impl OverlappingModel {
+
+    /// Learns the `n` x `n` patterns of the image at `sample_path` and prepares a `grid_size` x `grid_size` wave.
+    /// `symmetry` (1 to 8) is the number of rotated and reflected copies of each pattern to learn,
+    /// with `periodic_input` the patterns wrap around the borders of the sample and with `ground`
+    /// the bottom row is filled with the last pattern found in the sample.
+    pub fn new<P: AsRef<Path>>(sample_path: &P, n: usize, grid_size: usize, periodic_input: bool, symmetry: usize, ground: bool) -> Result<Self, WfcError> {
+        Self::with_dimensions(sample_path, n, grid_size, grid_size, periodic_input, symmetry, ground)
+    }
+
+    /// Same as [`new`](Self::new), with a `width` x `height` wave
+    pub fn with_dimensions<P: AsRef<Path>>(sample_path: &P, n: usize, width: usize, height: usize, periodic_input: bool, symmetry: usize, ground: bool) -> Result<Self, WfcError> {
+        let (bitmap, s_x, s_y) = bitmap_utils::load_bitmap(sample_path)?;
+        let (s_x, s_y) = (s_x as usize, s_y as usize);
+
+        if n == 0 || n > width || n > height || (!periodic_input && (n > s_x || n > s_y)) {
+            return Err(WfcError::InvalidParameter(format!("Pattern size {n} doesn't fit the sample or the grid")));
+        }
+        if !(1..=8).contains(&symmetry) {
+            return Err(WfcError::InvalidParameter(format!("Symmetry must be between 1 and 8, got {symmetry}")));
+        }
+
+        // Each pixel of the sample is replaced by the index of its color
+        let mut colors: Vec<u32> = Vec::new();
+        let mut color_indices: HashMap<u32, u32> = HashMap::new();
+        let sample: Vec<u32> = bitmap.iter().map(|&color| {
+            *color_indices.entry(color).or_insert_with(|| {
+                colors.push(color);
+                (colors.len() - 1) as u32
+            })
+        }).collect();
+
+        let mut patterns: Vec<Vec<u32>> = Vec::new();
+        let mut pattern_indices: HashMap<Vec<u32>, usize> = HashMap::new();
+        let mut weights: Vec<f32> = Vec::new();
+
+        let x_max = if periodic_input { s_x } else { s_x - n + 1 };
+        let y_max = if periodic_input { s_y } else { s_y - n + 1 };
+
+        for y in 0..y_max {
+            for x in 0..x_max {
+                let mut ps: Vec<Vec<u32>> = Vec::with_capacity(8);
+                ps.push(tile(|dx, dy| sample[(x + dx) % s_x + (y + dy) % s_y * s_x], n));
+                ps.push(reflect(&ps[0])?);
+                ps.push(rotate(&ps[0])?);
+                ps.push(reflect(&ps[2])?);
+                ps.push(rotate(&ps[2])?);
+                ps.push(reflect(&ps[4])?);
+                ps.push(rotate(&ps[4])?);
+                ps.push(reflect(&ps[6])?);
+
+                for p in ps.into_iter().take(symmetry) {
+                    match pattern_indices.get(&p) {
+                        Some(&index) => weights[index] += 1.0,
+                        None => {
+                            pattern_indices.insert(p.clone(), weights.len());
+                            weights.push(1.0);
+                            patterns.push(p);
+                        }
+                    }
+                }
+            }
+        }
+
+        let t = weights.len();
+        let mut propagator: Vec<Vec<Vec<usize>>> = vec![vec![Vec::new(); t]; 4];
+
+        for (d, direction) in propagator.iter_mut().enumerate() {
+            for (t1, compatible) in direction.iter_mut().enumerate() {
+                for t2 in 0..t {
+                    if Self::agrees(&patterns[t1], &patterns[t2], Model::DX[d], Model::DY[d], n) {
+                        compatible.push(t2);
+                    }
+                }
+            }
+        }
+
+        let mut model = Model::new(width, height, n, weights, propagator)?;
+        model.set_ground(ground);
+
+        Ok(OverlappingModel {
+            model,
+            patterns,
+            colors,
+            n,
+        })
+    }
+
+    /// Runs the algorithm for at most `limit` observations (-1 means until the wave has collapsed).
+    /// Fails with [`WfcError::Contradiction`] if a contradiction was reached.
+    pub fn run(&mut self, limit: isize, seed: [u8; 32]) -> Result<(), WfcError> {
+        self.model.run(limit, seed)
+    }
+
+    /// Saves the generated bitmap as an image at `path`
+    pub fn save(&self, path: &str) -> Result<(), WfcError> {
+        bitmap_utils::save_bitmap(path, &self.render(), self.model.width() as u32, self.model.height() as u32)
+    }
+
+    /// Returns the generated bitmap as rows of comma separated hex colors, undecided pixels showing
+    /// the average of the colors still allowed there
+    pub fn text_output(&self) -> String {
+        let mut result = String::new();
+
+        let bitmap = self.render();
+        for row in bitmap.chunks(self.model.width()) {
+            for pixel in row {
+                result.push_str(&format!("#{:02x}{:02x}{:02x}, ", pixel & 0xFF, (pixel >> 8) & 0xFF, (pixel >> 16) & 0xFF));
+            }
+            result.push('\n');
+        }
+
+        result
+    }
+
+    /* Accessors */
+
+    /// Side of the learned patterns
+    pub fn pattern_size(&self) -> usize {
+        self.n
+    }
+
+    /// Learned patterns, as indices into [`colors`](Self::colors)
+    pub fn patterns(&self) -> &[Vec<u32>] {
+        &self.patterns
+    }
+
+    /// Distinct packed RGBA colors found in the sample
+    pub fn colors(&self) -> &[u32] {
+        &self.colors
+    }
+
+    /// Observed pattern index for every cell, in row-major order
+    pub fn observed(&self) -> &[Option<usize>] {
+        self.model.observed()
+    }
+
+    /* Helper Functions */
+
+    /// One pixel per cell: every cell shows the top left pixel of its pattern, except the last
+    /// `n - 1` rows and columns which take the remaining pixels of the patterns next to them.
+    /// A pixel whose cell hasn't been observed yet is the average of the colors that the patterns
+    /// still allowed around it would give it, weighted by the pattern weights.
+    fn render(&self) -> Vec<u32> {
+        let (m_x, m_y) = (self.model.width(), self.model.height());
+        let n = self.n;
+        let observed = self.model.observed();
+        let (periodic_x, periodic_y) = self.model.periodic();
+        let mut bitmap = vec![0u32; m_x * m_y];
+
+        for y in 0..m_y {
+            let dy = if y < m_y - n + 1 { 0 } else { n - 1 };
+            for x in 0..m_x {
+                let dx = if x < m_x - n + 1 { 0 } else { n - 1 };
+                if let Some(t) = observed[x - dx + (y - dy) * m_x] {
+                    bitmap[x + y * m_x] = self.colors[self.patterns[t][dx + dy * n] as usize];
+                    continue;
+                }
+
+                let mut contributions: Vec<(u32, f32)> = Vec::new();
+                for dy in 0..n {
+                    for dx in 0..n {
+                        // Cell whose pattern covers (x, y) with its pixel (dx, dy)
+                        let sx = (x + m_x - dx) % m_x;
+                        let sy = (y + m_y - dy) % m_y;
+                        if (!periodic_x && (x < dx || sx + n > m_x)) || (!periodic_y && (y < dy || sy + n > m_y)) {
+                            continue;
+                        }
+                        let s = sx + sy * m_x;
+                        for t in 0..self.model.tile_count() {
+                            if self.model.is_allowed(s, t) {
+                                contributions.push((self.colors[self.patterns[t][dx + dy * n] as usize], self.model.weight_at(s, t)));
+                            }
+                        }
+                    }
+                }
+                bitmap[x + y * m_x] = bitmap_utils::blend(contributions);
+            }
+        }
+
+        bitmap
+    }
+
+    /// Whether `p2`, moved by (`dx`, `dy`), matches `p1` where the two overlap
+    fn agrees(p1: &[u32], p2: &[u32], dx: isize, dy: isize, n: usize) -> bool {
+        let n = n as isize;
+        let (x_min, x_max) = if dx < 0 { (0, dx + n) } else { (dx, n) };
+        let (y_min, y_max) = if dy < 0 { (0, dy + n) } else { (dy, n) };
+
+        for y in y_min..y_max {
+            for x in x_min..x_max {
+                if p1[(x + n * y) as usize] != p2[(x - dx + n * (y - dy)) as usize] {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Generator for OverlappingModel {
+    fn model(&self) -> &Model {
+        &self.model
+    }
+
+    fn model_mut(&mut self) -> &mut Model {
+        &mut self.model
+    }
+
+    fn save(&self, path: &str) -> Result<(), WfcError> {
+        OverlappingModel::save(self, path)
+    }
+
+    fn text_output(&self) -> String {
+        OverlappingModel::text_output(self)
+    }
+}
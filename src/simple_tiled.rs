@@ -1,47 +1,67 @@
 use roxmltree::{Document};
-use core::f32;
 use std::{collections::HashMap, fs};
-use rand::Rng;
-use rand::{SeedableRng, rngs::StdRng};
 use std::path::Path;
 
-use crate::{array_utils::{self, reflect, rotate}, bitmap_utils};
+use crate::{array_utils::{reflect, rotate}, bitmap_utils, connectivity::ConnectivityConstraint, constraint::Constraint, count::{self, Count, CountConstraint}, error::WfcError, model::{Generator, Model, Side}, tile_map::{Margins, TileMap}};
 
 pub struct SimpleTiledModel{
-    wave: Vec<Vec<bool>>,
-    observed: Vec<Option<usize>>,
+    model: Model,
 
-    propagator: Vec<Vec<Vec<usize>>>,
-    compatible: Vec<Vec<Vec<isize>>>,
+    tiles: Vec<Vec<u32>>,
+    tilenames: Vec<String>,
+    tilesize: u32,
+    /// Sides through which each tile carries each channel, see [`ConnectivityConstraint`]
+    channels: HashMap<String, Vec<u8>>,
+    /// Tile sets painted on region masks, see [`restrict_regions`](Self::restrict_regions)
+    regions: Vec<Region>,
+}
+
+/// A named set of tiles, declared by a `<region>` tag and painted with `color` on region masks
+struct Region {
+    name: String,
+    color: u32,
+    tiles: Vec<usize>,
+}
 
-    stack: Vec<(usize, usize)>,
+type SymmetryMap = fn(usize) -> usize;
 
-    m_x: usize,
-    m_y: usize,
+/// Everything `process_tiles` extracts from the `<tiles>` section
+struct TileData {
     t: usize,
-    n: usize,
-
+    tilesize: u32,
     weights: Vec<f32>,
-    weight_log_weights: Vec<f32>,
-    distribution: Vec<f32>,
-
-    sums_of_ones: Vec<usize>,
-    sum_of_weights: f32,
-    sum_of_weight_log_weights: f32,
-    starting_entropy: f32,
-
-    sums_of_weights: Vec<f32>,
-    sums_of_weight_log_weights: Vec<f32>,
-    entropies: Vec<f32>,
-
     tiles: Vec<Vec<u32>>,
     tilenames: Vec<String>,
-    tilesize: u32,
+    action: Vec<Vec<usize>>,
+    first_occurrence: HashMap<String, usize>,
+    /// Limits set by the `min` and `max` attributes of the tiles
+    counts: Vec<CountConstraint>,
+    channels: HashMap<String, Vec<u8>>,
 }
 
 impl SimpleTiledModel {
 
-    pub fn new<P: AsRef<Path>>(xml_path: &P, grid_size: usize) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Loads the tileset described by `xml_path` and prepares a `grid_size` x `grid_size` wave.
+    /// Tile images are looked up in `tilesets/<xml file stem>/`.
+    pub fn new<P: AsRef<Path>>(xml_path: &P, grid_size: usize) -> Result<Self, WfcError> {
+        Self::with_dimensions(xml_path, grid_size, grid_size)
+    }
+
+    /// Same as [`new`](Self::new), with a `width` x `height` wave
+    pub fn with_dimensions<P: AsRef<Path>>(xml_path: &P, width: usize, height: usize) -> Result<Self, WfcError> {
+        Self::with_subset(xml_path, None, width, height)
+    }
+
+    /// Same as [`with_dimensions`](Self::with_dimensions), using only the tiles listed in the
+    /// `<subset>` named `subset_name` (and the neighbor rules among them).
+    ///
+    /// A `<tile>` can limit how many cells hold any of its variants with the `min` and `max`
+    /// attributes, as a number of cells or a percentage of the grid (`max="3"`, `min="10%"`), and
+    /// list the sides of its first variant that carry a channel, such as a road, with
+    /// `<channel name="road" edges="left right"/>` children (see [`connect`](Self::connect)).
+    /// A `<regions>` section can name sets of tiles painted on region masks, see
+    /// [`restrict_regions`](Self::restrict_regions).
+    pub fn with_subset<P: AsRef<Path>>(xml_path: &P, subset_name: Option<&str>, width: usize, height: usize) -> Result<Self, WfcError> {
         let xml_content = fs::read_to_string(xml_path)?;
         let xml_string = xml_path.as_ref().to_string_lossy();
         let doc = Document::parse(&xml_content)?;
@@ -58,223 +78,284 @@ impl SimpleTiledModel {
             }
         };
 
-        let weights: Vec<f32> = Vec::new();
-        let tiles: Vec<Vec<u32>> = Vec::new();
-        let tilenames: Vec<String> = Vec::new();
-        let action: Vec<Vec<usize>> = Vec::new();
-        let first_occurrence: HashMap<String, usize> = HashMap::new();
+        let subset = match subset_name {
+            Some(name) => Some(Self::get_subset(&doc, name)?),
+            None => None,
+        };
 
-        let (t, tilesize, weights, tiles, tilenames, action, first_occurrence) = Self::process_tiles(&doc, &domain_name, &unique, weights, tiles, tilenames, action, first_occurrence)?;
+        let TileData { t, tilesize, weights, tiles, tilenames, action, first_occurrence, counts, channels } = Self::process_tiles(&doc, &domain_name, &unique, subset.as_ref())?;
 
-        let propagator = Self::get_propagator(&doc, t, first_occurrence, action)?;
+        let regions = Self::get_regions(&doc, &tilenames, subset_name)?;
+        let propagator = Self::get_propagator(&doc, t, first_occurrence, action, subset.as_ref())?;
 
-        let weight_log_weights: Vec<f32> = weights.iter().map(|&w| w * w.ln()).collect();
-        let sum_of_weights: f32 = weights.iter().sum();
-        let sum_of_weight_log_weights: f32 = weight_log_weights.iter().sum();
+        let mut model = Model::new(width, height, 1, weights, propagator)?;
+        for count in counts {
+            model.add_constraint(count);
+        }
 
         Ok(SimpleTiledModel { 
-            wave: vec![vec![true; t]; grid_size * grid_size], 
-            observed: vec![None; grid_size * grid_size],
-            propagator: propagator, 
-            compatible: vec![vec![vec![0; 4]; t]; grid_size * grid_size], 
-            stack: Vec::new(), 
-            m_x: grid_size, 
-            m_y: grid_size, 
-            t: t, 
-            n: 1, 
-            weights: weights, 
-            weight_log_weights: weight_log_weights, 
-            distribution: vec![0f32;t], 
-            sums_of_ones: vec![0; grid_size * grid_size], 
-            sum_of_weights: sum_of_weights, 
-            sum_of_weight_log_weights: sum_of_weight_log_weights, 
-            starting_entropy: sum_of_weights.ln() - (sum_of_weight_log_weights / sum_of_weights), 
-            sums_of_weights: vec![0f32; grid_size * grid_size], 
-            sums_of_weight_log_weights: vec![0f32; grid_size * grid_size], 
-            entropies: vec![0f32; grid_size * grid_size], 
-            tiles: tiles, 
-            tilenames: tilenames, 
-            tilesize: tilesize
+            model,
+            tiles, 
+            tilenames, 
+            tilesize,
+            channels,
+            regions,
         })
     }
 
-    pub fn run(&mut self, limit: isize, seed: [u8; 32]) -> bool {
-        self.clear();
+    /// Same as [`with_subset`](Self::with_subset), with a grid made of `map` grown by `margins`:
+    /// the cells of the map are pinned, so that a run only generates the new ones around it
+    pub fn outpaint<P: AsRef<Path>>(xml_path: &P, subset_name: Option<&str>, map: &TileMap, margins: Margins) -> Result<Self, WfcError> {
+        let width = margins.left + map.width() + margins.right;
+        let height = margins.top + map.height() + margins.bottom;
 
-        let mut rng = StdRng::from_seed(seed);
-        let mut l = 0;
-        loop {
-            if limit >= 0 && l >= limit {
-                break;
-            }
+        let mut model = Self::with_subset(xml_path, subset_name, width, height)?;
+        model.pin_map(map, margins.left, margins.top, |_, _| false)?;
+        Ok(model)
+    }
 
-            if let Some(node) = self.next_unobserved_node(&mut rng) {
-                self.observe(node, &mut rng);
-                let success = self.propagate();
-                if !success {
-                    return false;
-                }
-            } else {
-                for i in 0..self.wave.len() {
-                    for t in 0..self.t {
-                        if self.wave[i][t] {
-                            self.observed[i] = Some(t);
-                        }
-                    }
-                }
-                return true;
-            }
-            l += 1;
-        }
+    /// Runs the algorithm for at most `limit` observations (-1 means until the wave has collapsed).
+    /// Fails with [`WfcError::Contradiction`] if a contradiction was reached.
+    pub fn run(&mut self, limit: isize, seed: [u8; 32]) -> Result<(), WfcError> {
+        self.model.run(limit, seed)
+    }
 
-        true
+    /// Adds a game specific rule checked during every following run, inside the same
+    /// propagation loop as the neighbor rules, see [`Constraint`]
+    pub fn add_constraint<C: Constraint + 'static>(&mut self, constraint: C) {
+        self.model.add_constraint(constraint);
     }
 
-    fn next_unobserved_node(&mut self, rng: &mut StdRng) -> Option<usize> {
-        let mut min = f32::MAX;
-        let mut argmin: Option<usize> = None;
+    /// Keeps the number of cells holding any of `tiles`, named like in [`pin`](Self::pin), between
+    /// `min` and `max` in the following runs, see [`CountConstraint`]
+    pub fn limit_count(&mut self, tiles: &[&str], min: Option<Count>, max: Option<Count>) -> Result<(), WfcError> {
+        let mut group = Vec::new();
+        for tile in tiles {
+            group.extend(self.tile_indices(tile)?);
+        }
+        if count::unsatisfiable(min, max) {
+            return Err(WfcError::InvalidParameter(format!(
+                "The count of {} can't be at least {} and at most {}", tiles.join(", "), min.unwrap(), max.unwrap()
+            )));
+        }
+        self.model.add_constraint(CountConstraint::new(group, min, max));
+        Ok(())
+    }
 
-        for i in 0..self.wave.len() {
-            if i % self.m_x + self.n > self.m_x || i / self.m_x + self.n > self.m_y {
-                continue;
-            }
+    /// Makes every cell carrying `channel` belong to a single network in the following runs,
+    /// see [`ConnectivityConstraint`]
+    pub fn connect(&mut self, channel: &str) -> Result<(), WfcError> {
+        let edges = self.channel_edges(channel)?;
+        self.model.add_constraint(ConnectivityConstraint::new(edges));
+        Ok(())
+    }
 
-            let remaining_values = self.sums_of_ones[i];
+    /// Makes the cells at `points` carry `channel` and be linked to each other in the following runs,
+    /// while the rest of the channel is free to form other networks
+    pub fn connect_points(&mut self, channel: &str, points: &[(usize, usize)]) -> Result<(), WfcError> {
+        let edges = self.channel_edges(channel)?;
+        if let Some((x, y)) = points.iter().find(|&&(x, y)| x >= self.width() || y >= self.height()) {
+            return Err(WfcError::InvalidParameter(format!("Point ({x}, {y}) is outside of the {}x{} grid", self.width(), self.height())));
+        }
+        self.model.add_constraint(ConnectivityConstraint::between(edges, points.to_vec()));
+        Ok(())
+    }
 
-            let entropy = self.entropies[i];
+    /// Scales the weights of `tiles`, named like in [`pin`](Self::pin), cell by cell with the image at
+    /// `path`, which has one pixel per cell: the weights are multiplied by `2^(strength * (2 * l - 1))`,
+    /// where `l` is the luminance of the pixel, so that white cells favor the tiles, black ones avoid
+    /// them and mid gray leaves them alone. Maps applied one after the other multiply, see
+    /// [`Model::set_cell_weights`]
+    pub fn weight_map<P: AsRef<Path>>(&mut self, path: &P, tiles: &[&str], strength: f32) -> Result<(), WfcError> {
+        let (luminance, width, height) = bitmap_utils::load_luminance(path)?;
+        if (width as usize, height as usize) != (self.width(), self.height()) {
+            return Err(WfcError::InvalidParameter(format!("The weight map is {width}x{height}, the grid is {}x{}", self.width(), self.height())));
+        }
 
-            if remaining_values > 1 && entropy <= min {
-                let noise: f32 = 1E-6 * rng.random::<f32>();
-                if entropy + noise < min {
-                    min = entropy + noise;
-                    argmin = Some(i);
-                }
+        let mut scaled = vec![false; self.tile_count()];
+        for tile in tiles {
+            for t in self.tile_indices(tile)? {
+                scaled[t] = true;
             }
         }
 
-        argmin
+        let weights = (0..self.width() * self.height())
+            .flat_map(|i| (0..self.tile_count()).map(move |t| (i, t)))
+            .map(|(i, t)| {
+                let weight = self.model.weight_at(i, t);
+                if scaled[t] { weight * (strength * (2.0 * luminance[i] - 1.0)).exp2() } else { weight }
+            })
+            .collect();
+        self.model.set_cell_weights(Some(weights))
     }
 
-    fn observe(&mut self, node: usize, rng: &mut StdRng) -> bool {
-       for t in 0..self.t {
-            self.distribution[t] = match self.wave[node][t] {
-                true => self.weights[t],
-                false => 0f32,
-            };
-       }
+    /// Restricts every cell to the tiles of its region in the following runs, as painted on the image
+    /// at `path`, which has one pixel per cell in the colors of the `<region>` tags of the tileset:
+    ///
+    /// ```xml
+    /// <regions>
+    ///   <region name="water" color="#3060c0">
+    ///     <tile name="water_a"/>
+    ///     <tile name="waterside"/>
+    ///   </region>
+    /// </regions>
+    /// ```
+    ///
+    /// Transparent pixels leave their cell free, any other color has to belong to a region. Cells
+    /// already made inactive with [`set_active_mask`](Self::set_active_mask) are skipped. The
+    /// neighbor rules still apply, so the tiles along the border between two regions have to fit both.
+    pub fn restrict_regions<P: AsRef<Path>>(&mut self, path: &P) -> Result<(), WfcError> {
+        let (pixels, width, height) = bitmap_utils::load_bitmap(path)?;
+        if (width as usize, height as usize) != (self.width(), self.height()) {
+            return Err(WfcError::InvalidParameter(format!("The region mask is {width}x{height}, the grid is {}x{}", self.width(), self.height())));
+        }
 
-       let r = array_utils::weighted_random(&self.distribution, rng.random::<f32>());
-       for t in 0..self.t {
-            if self.wave[node][t] != (t == r) {
-                self.ban(node, t);
+        for (i, &pixel) in pixels.iter().enumerate() {
+            if pixel >> 24 == 0 || !self.model.is_active(i) {
+                continue;
             }
-       }
-       true
+            let (x, y) = (i % self.width(), i / self.width());
+            let region = self.regions.iter()
+                .find(|region| region.color == pixel | 0xFF << 24)
+                .ok_or_else(|| WfcError::InvalidParameter(format!(
+                    "Pixel ({x}, {y}) of the region mask has color #{:02x}{:02x}{:02x}, which no region uses", pixel & 0xFF, (pixel >> 8) & 0xFF, (pixel >> 16) & 0xFF
+                )))?;
+            self.model.preset(x, y, &region.tiles)?;
+        }
+        Ok(())
     }
 
-    fn propagate(&mut self) -> bool {
-        while let Some((position, tile)) = self.stack.pop() {
-            let position_x = position % self.m_x;
-            let position_y = position / self.m_x;
+    /// Names of the regions declared by the tileset, see [`restrict_regions`](Self::restrict_regions)
+    pub fn region_names(&self) -> Vec<&str> {
+        self.regions.iter().map(|region| region.name.as_str()).collect()
+    }
 
-            for d in 0..4 {
-                let position_x_move = position_x as isize + Self::DX[d];
-                let position_y_move = position_y as isize + Self::DY[d];
+    /// Limits the generation to the white cells of the black and white image at `path`, which has one
+    /// pixel per cell, in the following runs: the black (or transparent) ones are left empty and treated
+    /// as outside of the grid, so the cells next to them only get tiles that can sit next to the tile set
+    /// with [`set_boundary`](Self::set_boundary) for that side, if any. See [`Model::set_active`]
+    pub fn set_active_mask<P: AsRef<Path>>(&mut self, path: &P) -> Result<(), WfcError> {
+        let (active, width, height) = bitmap_utils::load_mask(path)?;
+        if (width as usize, height as usize) != (self.width(), self.height()) {
+            return Err(WfcError::InvalidParameter(format!("The active mask is {width}x{height}, the grid is {}x{}", self.width(), self.height())));
+        }
+        self.model.set_active(Some(active))
+    }
 
-                if position_x_move < 0 || position_y_move < 0 || 
-                   position_x_move + self.n as isize > self.m_x as isize || 
-                   position_y_move + self.n as isize > self.m_y as isize {
-                    continue;
-                }
-                
-                let position_move = position_x_move as usize + position_y_move as usize * self.m_x;
+    /// Frames `side` of the grid with `tile`, named like in [`pin`](Self::pin), in the following runs:
+    /// the cells along it only get tiles that can sit next to it. `None` leaves the side open, see
+    /// [`Model::set_boundary`]
+    pub fn set_boundary(&mut self, side: Side, tile: Option<&str>) -> Result<(), WfcError> {
+        let tiles = tile.map(|tile| self.tile_indices(tile)).transpose()?;
+        self.model.set_boundary(side, tiles.as_deref())
+    }
 
-                for neighbor_tile in self.propagator[d][tile].clone() {
-                    self.compatible[position_move][neighbor_tile][d] -= 1;
-                    if self.compatible[position_move][neighbor_tile][d] == 0 {
-                        self.ban(position_move, neighbor_tile);
-                    }
-                }
-            }
+    /// Pins the cell at (`x`, `y`) to `tile` for the following runs: `"<name> <variant>"` is a
+    /// single tile, as listed in [`tile_names`](Self::tile_names), while a bare `"<name>"` allows
+    /// any of its variants
+    pub fn pin(&mut self, x: usize, y: usize, tile: &str) -> Result<(), WfcError> {
+        self.pin_any(x, y, &[tile])
+    }
+
+    /// Restricts the cell at (`x`, `y`) to any of `tiles`, named like in [`pin`](Self::pin)
+    pub fn pin_any(&mut self, x: usize, y: usize, tiles: &[&str]) -> Result<(), WfcError> {
+        let mut allowed = Vec::new();
+        for tile in tiles {
+            allowed.extend(self.tile_indices(tile)?);
         }
-        self.sums_of_ones[0] > 0
+        self.model.preset(x, y, &allowed)
     }
 
-    fn ban(&mut self, i: usize, t: usize){
-        self.wave[i][t] = false;
+    /// Reads pins from the file at `path`, one per line in the form `<x> <y> <tile> [variant]`.
+    /// Alternatives for the same cell are separated by commas (`3 0 road, bridge 1`), empty lines
+    /// and lines starting with `#` are ignored.
+    pub fn pin_from_file<P: AsRef<Path>>(&mut self, path: &P) -> Result<(), WfcError> {
+        let content = fs::read_to_string(path)?;
 
-        for d in 0..4 {
-            self.compatible[i][t][d] = 0;
-        }
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
 
-        self.stack.push((i, t));
+            // Any run of spaces or tabs separates the coordinates, the rest of the line lists the tiles
+            let mut parts = line.split_whitespace();
+            let coordinates = (parts.next().and_then(|x| x.parse().ok()), parts.next().and_then(|y| y.parse().ok()));
+            let tiles = parts.collect::<Vec<_>>().join(" ");
+            let (Some(x), Some(y), false) = (coordinates.0, coordinates.1, tiles.is_empty()) else {
+                return Err(WfcError::InvalidParameter(format!(
+                    "{}:{}: expected \"<x> <y> <tile> [variant]\", found \"{line}\"", path.as_ref().display(), number + 1
+                )));
+            };
 
-        self.sums_of_ones[i] -= 1;
-        self.sums_of_weights[i] -= self.weights[t];
-        self.sums_of_weight_log_weights[i] -= self.weight_log_weights[t];
-        
-        if self.sums_of_weights[i] > 0.0 {
-            self.entropies[i] = self.sums_of_weights[i].ln() - self.sums_of_weight_log_weights[i] / self.sums_of_weights[i];
-        } else {
-            self.entropies[i] = f32::NEG_INFINITY;
+            let tiles: Vec<&str> = tiles.split(',').map(str::trim).collect();
+            self.pin_any(x, y, &tiles)?;
         }
+        Ok(())
     }
 
-    fn clear(&mut self){
-        for i in 0..self.wave.len() {
-            for t in 0..self.t {
-                self.wave[i][t] = true;
-
-                for d in 0..4 {
-                    self.compatible[i][t][d] = self.propagator[Self::OPPOSITE[d]][t].len() as isize;
+    /// Pins the cells of `map`, placed with its top left corner at (`x`, `y`), so that a new run only
+    /// fills the rest of the grid: the unobserved cells of the map and those for which
+    /// `regenerate(map_x, map_y)` returns `true` are left free
+    pub fn pin_map<F: Fn(usize, usize) -> bool>(&mut self, map: &TileMap, x: usize, y: usize, regenerate: F) -> Result<(), WfcError> {
+        for map_y in 0..map.height() {
+            for map_x in 0..map.width() {
+                if let Some(tile) = map.get(map_x, map_y) && !regenerate(map_x, map_y) {
+                    self.pin(x + map_x, y + map_y, tile)?;
                 }
             }
-            self.sums_of_ones[i] = self.t;
-            self.sums_of_weights[i] = self.sum_of_weights;
-            self.sums_of_weight_log_weights[i] = self.sum_of_weight_log_weights;
-            self.entropies[i] = self.starting_entropy;
-        }
-        
-        for i in 0..self.observed.len() {
-            self.observed[i] = None;
         }
+        Ok(())
     }
 
-    pub fn save(&self, path: &str) {
-        let total_size = self.m_x * self.m_y * (self.tilesize * self.tilesize) as usize;
+    /// Saves the generated map as an image at `path`. Cells that haven't been observed yet show
+    /// the average of the tiles still allowed there, weighted by the weights of the cell, inactive cells
+    /// are transparent
+    pub fn save(&self, path: &str) -> Result<(), WfcError> {
+        let (m_x, m_y) = (self.model.width(), self.model.height());
+        let observed = self.model.observed();
+        let total_size = m_x * m_y * (self.tilesize * self.tilesize) as usize;
         let mut bitmap = vec![0u32; total_size];
         
-        if self.observed[0].is_some() {
-            for x in 0..self.m_x {
-                for y in 0..self.m_y {
-                    let i = x + y * self.m_x;
-                    if let Some(t) = self.observed[i] {
-                        let tile = &self.tiles[t];
-                        for dy in 0..self.tilesize as usize {
-                            for dx in 0..self.tilesize as usize {
-                                let bitmap_idx = (x * self.tilesize as usize + dx) + 
-                                               (y * self.tilesize as usize + dy) * (self.m_x * self.tilesize as usize);
-                                let tile_idx = dx + dy * self.tilesize as usize;
-                                if bitmap_idx < bitmap.len() && tile_idx < tile.len() {
-                                    bitmap[bitmap_idx] = tile[tile_idx];
-                                }
-                            }
+        for x in 0..m_x {
+            for y in 0..m_y {
+                let i = x + y * m_x;
+                if !self.model.is_active(i) {
+                    continue;
+                }
+                let superposition;
+                let tile = match observed[i] {
+                    Some(t) => &self.tiles[t],
+                    None => {
+                        superposition = self.superposition(i);
+                        &superposition
+                    }
+                };
+                for dy in 0..self.tilesize as usize {
+                    for dx in 0..self.tilesize as usize {
+                        let bitmap_idx = (x * self.tilesize as usize + dx) + 
+                                       (y * self.tilesize as usize + dy) * (m_x * self.tilesize as usize);
+                        let tile_idx = dx + dy * self.tilesize as usize;
+                        if bitmap_idx < bitmap.len() && tile_idx < tile.len() {
+                            bitmap[bitmap_idx] = tile[tile_idx];
                         }
                     }
                 }
             }
         }
 
-        bitmap_utils::save_bitmap(path, &bitmap, self.m_x as u32 * self.tilesize, self.m_y as u32 * self.tilesize);
+        bitmap_utils::save_bitmap(path, &bitmap, m_x as u32 * self.tilesize, m_y as u32 * self.tilesize)
     }
 
+    /// Returns the generated map as rows of comma separated tile names, inactive cells are left blank
     pub fn text_output(&self) -> String {
         let mut result = String::new();
         
-        for y in 0..self.m_y {
-            for x in 0..self.m_x {
-                let index = x + y * self.m_x;
-                if let Some(tile_idx) = self.observed[index] {
+        for y in 0..self.model.height() {
+            for x in 0..self.model.width() {
+                if !self.model.is_active(x + y * self.model.width()) {
+                    result.push_str(", ");
+                } else if let Some(tile_idx) = self.model.observed_at(x, y) {
                     result.push_str(&format!("{}, ", self.tilenames[tile_idx]));
                 } else {
                     result.push_str("unobserved, ");
@@ -286,19 +367,106 @@ impl SimpleTiledModel {
         result
     }
 
+    /// The generated map as tile names, to be saved and pinned into a later generation
+    pub fn tile_map(&self) -> TileMap {
+        let cells = self.model.observed().iter()
+            .map(|observed| observed.map(|t| self.tilenames[t].clone()))
+            .collect();
+        TileMap { width: self.model.width(), height: self.model.height(), cells }
+    }
+
+    /* Accessors */
+
+    /// Width of the grid, in tiles
+    pub fn width(&self) -> usize {
+        self.model.width()
+    }
+
+    /// Height of the grid, in tiles
+    pub fn height(&self) -> usize {
+        self.model.height()
+    }
+
+    /// Number of tiles, counting every variant generated by the symmetry system
+    pub fn tile_count(&self) -> usize {
+        self.model.tile_count()
+    }
+
+    /// Size in pixels of the side of a tile
+    pub fn tile_size(&self) -> u32 {
+        self.tilesize
+    }
+
+    /// Names of the tiles in the form `"<name> <variant>"`, indexed like the tiles themselves
+    pub fn tile_names(&self) -> &[String] {
+        &self.tilenames
+    }
+
+    /// Packed RGBA pixels of each tile
+    pub fn tiles(&self) -> &[Vec<u32>] {
+        &self.tiles
+    }
+
+    /// Observed tile index for every cell, in row-major order. `None` means the cell has not been observed yet
+    pub fn observed(&self) -> &[Option<usize>] {
+        self.model.observed()
+    }
+
+    /// Observed tile index of the cell at (`x`, `y`)
+    pub fn observed_at(&self, x: usize, y: usize) -> Option<usize> {
+        self.model.observed_at(x, y)
+    }
+
     /* Helper Functions */
-    fn get_cardinality_a_b_on_symmetry(symmetry: &str) -> (usize, fn(usize) -> usize, fn(usize) -> usize){
+
+    /// Indices of the tiles matching `tile`: the exact `"<name> <variant>"` or every variant of `"<name>"`
+    fn tile_indices(&self, tile: &str) -> Result<Vec<usize>, WfcError> {
+        let tile = tile.split_whitespace().collect::<Vec<_>>().join(" ");
+        let indices: Vec<usize> = match self.tilenames.iter().position(|name| *name == tile) {
+            Some(t) => vec![t],
+            None => self.tilenames.iter()
+                .enumerate()
+                .filter(|(_, name)| name.rsplit_once(' ').is_some_and(|(base, _)| base == tile))
+                .map(|(t, _)| t)
+                .collect(),
+        };
+
+        if indices.is_empty() {
+            return Err(WfcError::UnknownTile(tile));
+        }
+        Ok(indices)
+    }
+
+    /// Sides of every tile carrying `channel`, as declared by the `<channel>` tags
+    fn channel_edges(&self, channel: &str) -> Result<Vec<u8>, WfcError> {
+        self.channels.get(channel).cloned().ok_or_else(|| WfcError::UnknownChannel(channel.to_string()))
+    }
+
+    /// Pixels of cell `i` averaging the tiles still allowed there, weighted like in the cell
+    fn superposition(&self, i: usize) -> Vec<u32> {
+        let allowed: Vec<(usize, f32)> = (0..self.model.tile_count())
+            .filter(|&t| self.model.is_allowed(i, t))
+            .map(|t| (t, self.model.weight_at(i, t)))
+            .collect();
+
+        (0..(self.tilesize * self.tilesize) as usize)
+            .map(|idx| bitmap_utils::blend(allowed.iter().map(|&(t, weight)| (self.tiles[t][idx], weight))))
+            .collect()
+    }
+
+    pub(crate) fn get_cardinality_a_b_on_symmetry(symmetry: &str) -> Option<(usize, SymmetryMap, SymmetryMap)>{
         match symmetry {
-            "L" => (4, |x| (x+1)%4, |x| if x % 2 == 0 {x + 1} else { x - 1}),
-            "T" => (4, |x| (x+1)%4, |x| if x % 2 == 0 {x} else { 4 - x}),
-            "I" => (2, |x| 1 - x, |x| x),
-            "\\" => (2, |x| 1 - x, |x| 1 - x),
-            "F" => (8, |x| if x < 4 {(x + 1) % 4} else { 4 + (x - 1) % 4}, |x| if x < 4 {x + 4} else { x - 4}),
-            _ => (1, |x| x, |x| x)
+            "L" => Some((4, |x| (x+1)%4, |x| if x % 2 == 0 {x + 1} else { x - 1})),
+            "T" => Some((4, |x| (x+1)%4, |x| if x % 2 == 0 {x} else { 4 - x})),
+            "I" => Some((2, |x| 1 - x, |x| x)),
+            "\\" => Some((2, |x| 1 - x, |x| 1 - x)),
+            "F" => Some((8, |x| if x < 4 {(x + 1) % 4} else { 4 + (x - 1) % 4}, |x| if x < 4 {x + 4} else { x - 4})),
+            "X" => Some((1, |x| x, |x| x)),
+            _ => None
         }
     }
 
-    fn get_map_row(i: usize, t: usize, a: fn(usize) -> usize, b: fn(usize) -> usize) -> Vec<usize> {
+    fn get_map_row(i: usize, t: usize, a: SymmetryMap, b: SymmetryMap) -> Vec<usize> {
         let mut map_row = vec![0; 8];
         map_row[0] = i;
         map_row[1] = a(i);
@@ -309,57 +477,189 @@ impl SimpleTiledModel {
         map_row[6] = b(a(a(i)));
         map_row[7] = b(a(a(a(i))));
 
-        for s in 0..8 {
-            map_row[s] += t;
+        for value in map_row.iter_mut() {
+            *value += t;
         }
 
         map_row
     }
 
-    fn load_tiles_bitmap(domain_name: &String, unique: &bool, tile_name: &String, mut tiles: Vec<Vec<u32>>, variants: usize, t: usize) -> Result<(Vec<Vec<u32>>, u32), Box<dyn std::error::Error>>{
-        let mut tilesize = 0;
+    /// Loads the images of every variant of a tile. `tilesize` is the size of the tiles loaded so far, if any
+    fn load_tiles_bitmap(domain_name: &String, unique: &bool, tile_name: &String, tiles: &mut Vec<Vec<u32>>, variants: usize, t: usize, tilesize: Option<u32>) -> Result<u32, WfcError>{
+        let load_square = |path: String, name: String| -> Result<(Vec<u32>, u32), WfcError> {
+            let (bitmap, width, height) = bitmap_utils::load_bitmap(path)?;
+            let expected = tilesize.unwrap_or(width);
+            if width != height || width != expected {
+                return Err(WfcError::TileSizeMismatch { tile: name, width, height, expected });
+            }
+            Ok((bitmap, width))
+        };
+
+        let mut size = 0;
 
         if *unique {
             for i in 0..variants {
-                let (bitmap, ts, _) = bitmap_utils::load_bitmap(&format!("tilesets/{}/{} {i}.png", domain_name, tile_name));
-                tilesize = ts;
+                let (bitmap, ts) = load_square(format!("tilesets/{}/{} {i}.png", domain_name, tile_name), format!("{tile_name} {i}"))?;
+                size = ts;
                 tiles.push(bitmap);
             }
 
         } else {
-            let (bitmap, ts, _) = bitmap_utils::load_bitmap(&format!("tilesets/{}/{}.png", domain_name, tile_name));
-            tilesize = ts;
+            let (bitmap, ts) = load_square(format!("tilesets/{}/{}.png", domain_name, tile_name), tile_name.clone())?;
+            size = ts;
             tiles.push(bitmap);
             for i in 1..variants {
                 if i <= 3 {
-                    tiles.push(rotate(&tiles[t + i - 1]));
+                    tiles.push(rotate(&tiles[t + i - 1])?);
                 }
                 if i >= 4 {
-                    tiles.push(reflect(&tiles[t + i - 4]));
+                    tiles.push(reflect(&tiles[t + i - 4])?);
                 }
             }
         }
 
-        Ok((tiles, tilesize))
+        Ok(size)
+    }
+
+    /// Bitmask of the sides listed in `sides` (`"left top"`), bit `d` standing for direction `d`
+    pub(crate) fn parse_edges(sides: &str) -> Option<u8> {
+        sides.split_whitespace().try_fold(0, |edges, side| {
+            let d = ["left", "bottom", "right", "top"].iter().position(|&name| name == side)?;
+            Some(edges | 1 << d)
+        })
     }
 
-    fn process_tiles(doc: &Document, domain_name: &String, unique: &bool, mut weights: Vec<f32>, mut tiles: Vec<Vec<u32>>, mut tilenames: Vec<String>, mut action: Vec<Vec<usize>>, mut first_occurrence: HashMap<String, usize>) -> Result<(usize, u32, Vec<f32>, Vec<Vec<u32>>, Vec<String>, Vec<Vec<usize>>, HashMap<String, usize>), Box<dyn std::error::Error>>{
+    /// Sides of variant `variant` of a tile whose first variant has `edges`: variants 1 to 3 turn it a
+    /// quarter counterclockwise each time, variants 4 to 7 mirror the first four left to right
+    fn transform_edges(edges: u8, variant: usize) -> u8 {
+        (0..4).filter(|d| edges >> d & 1 == 1)
+            .map(|d| {
+                let turned = (d + variant % 4) % 4;
+                if variant < 4 || turned % 2 == 1 { turned } else { 2 - turned }
+            })
+            .fold(0, |result, d| result | 1 << d)
+    }
+
+    /// Names of the tiles listed in the subset called `name`
+    fn get_subset(doc: &Document, name: &str) -> Result<Vec<String>, WfcError> {
+        let subset_tag = doc.descendants()
+            .find(|n| n.has_tag_name("subsets"))
+            .ok_or_else(|| WfcError::MissingXml("Tag <subsets>".to_string()))?
+            .children()
+            .find(|n| n.has_tag_name("subset") && n.attribute("name") == Some(name))
+            .ok_or_else(|| WfcError::UnknownSubset(name.to_string()))?;
+
+        Ok(subset_tag.children()
+            .filter(|n| n.has_tag_name("tile"))
+            .filter_map(|n| n.attribute("name"))
+            .map(|name| name.to_string())
+            .collect())
+    }
+
+    /// Regions declared in the `<regions>` section, if any. Tiles left out by the subset are skipped,
+    /// but a region can't lose all of them
+    fn get_regions(doc: &Document, tilenames: &[String], subset_name: Option<&str>) -> Result<Vec<Region>, WfcError> {
+        let Some(regions_tag) = doc.descendants().find(|n| n.has_tag_name("regions")) else {
+            return Ok(Vec::new());
+        };
+        let declared: Vec<&str> = doc.descendants()
+            .find(|n| n.has_tag_name("tiles"))
+            .map(|tiles_tag| tiles_tag.children().filter(|n| n.has_tag_name("tile")).filter_map(|n| n.attribute("name")).collect())
+            .unwrap_or_default();
+
+        let mut regions = Vec::new();
+        for node in regions_tag.children().filter(|n| n.has_tag_name("region")) {
+            let name = node.attribute("name")
+                .ok_or_else(|| WfcError::MissingXml("Attribute name of <region>".to_string()))?;
+            let value = node.attribute("color")
+                .ok_or_else(|| WfcError::MissingXml(format!("Attribute color of <region> \"{name}\"")))?;
+            let color = bitmap_utils::parse_color(value)
+                .ok_or_else(|| WfcError::InvalidParameter(format!("Region \"{name}\" has color \"{value}\", expected #rrggbb")))?;
+
+            let mut tiles = Vec::new();
+            for tile in node.children().filter(|n| n.has_tag_name("tile")) {
+                let tile = tile.attribute("name")
+                    .ok_or_else(|| WfcError::MissingXml(format!("Attribute name of <tile> in region \"{name}\"")))?;
+                let base = tile.split_whitespace().next().unwrap_or_default();
+                if !declared.contains(&base) {
+                    return Err(WfcError::UnknownTile(tile.to_string()));
+                }
+                let tile = tile.split_whitespace().collect::<Vec<_>>().join(" ");
+                tiles.extend(tilenames.iter()
+                    .enumerate()
+                    .filter(|(_, t)| **t == tile || t.rsplit_once(' ').is_some_and(|(b, _)| b == tile))
+                    .map(|(t, _)| t));
+            }
+            if let Some(subset_name) = subset_name && tiles.is_empty() {
+                return Err(WfcError::InvalidParameter(format!("Subset \"{subset_name}\" leaves no tile in region \"{name}\"")));
+            }
+
+            regions.push(Region { name: name.to_string(), color, tiles });
+        }
+        Ok(regions)
+    }
+
+    fn process_tiles(doc: &Document, domain_name: &String, unique: &bool, subset: Option<&Vec<String>>) -> Result<TileData, WfcError>{
         let tiles_tag = doc.descendants()
             .find(|n| n.has_tag_name("tiles"))
-            .ok_or_else(|| "Tag <tiles> not found in the document!")?;
+            .ok_or_else(|| WfcError::MissingXml("Tag <tiles>".to_string()))?;
+
+        let mut weights: Vec<f32> = Vec::new();
+        let mut tiles: Vec<Vec<u32>> = Vec::new();
+        let mut tilenames: Vec<String> = Vec::new();
+        let mut action: Vec<Vec<usize>> = Vec::new();
+        let mut first_occurrence: HashMap<String, usize> = HashMap::new();
+        let mut counts: Vec<CountConstraint> = Vec::new();
+        let mut channels: HashMap<String, Vec<u8>> = HashMap::new();
 
         let mut t: usize;
-        let mut tilesize: u32 = 14;
+        let mut tilesize: Option<u32> = None;
 
         for node in tiles_tag.children().filter(|n| n.has_tag_name("tile")){
-            let tile_name: String = node.attribute("name").unwrap_or("").to_string();
+            let tile_name: String = node.attribute("name")
+                .ok_or_else(|| WfcError::MissingXml("Attribute name of <tile>".to_string()))?
+                .to_string();
+            if subset.is_some_and(|subset| !subset.contains(&tile_name)) {
+                continue;
+            }
+            // Anything but a positive number would break the entropy, validate warns about it
             let weight = node.attribute("weight")
                 .and_then(|w| w.parse::<f32>().ok())
+                .filter(|w| w.is_finite() && *w > 0.0)
                 .unwrap_or(1.0);
-            let (variants, a, b) = Self::get_cardinality_a_b_on_symmetry(node.attribute("symmetry").unwrap_or(""));
+            let symmetry = node.attribute("symmetry").unwrap_or("X");
+            let (variants, a, b) = Self::get_cardinality_a_b_on_symmetry(symmetry)
+                .ok_or_else(|| WfcError::BadSymmetry { tile: tile_name.clone(), symmetry: symmetry.to_string() })?;
+
+            let count = |attribute: &str| node.attribute(attribute)
+                .map(|value| value.parse::<Count>()
+                    .map_err(|_| WfcError::InvalidParameter(format!("Tile \"{tile_name}\" has {attribute}=\"{value}\", which is neither a number of cells nor a percentage"))))
+                .transpose();
+            let (min, max) = (count("min")?, count("max")?);
+            if count::unsatisfiable(min, max) {
+                return Err(WfcError::InvalidParameter(format!("Tile \"{tile_name}\" has a min greater than its max")));
+            }
 
             t = action.len();
             first_occurrence.insert(tile_name.clone(), t);
+            if min.is_some() || max.is_some() {
+                counts.push(CountConstraint::new((t..t + variants).collect(), min, max));
+            }
+
+            for channel in node.children().filter(|n| n.has_tag_name("channel")) {
+                let name = channel.attribute("name")
+                    .ok_or_else(|| WfcError::MissingXml(format!("Attribute name of <channel> in tile \"{tile_name}\"")))?;
+                let sides = channel.attribute("edges")
+                    .ok_or_else(|| WfcError::MissingXml(format!("Attribute edges of <channel> in tile \"{tile_name}\"")))?;
+                let edges = Self::parse_edges(sides)
+                    .ok_or_else(|| WfcError::InvalidParameter(format!("Tile \"{tile_name}\" has channel edges \"{sides}\", expected sides among left, bottom, right and top")))?;
+
+                let tile_edges = channels.entry(name.to_string()).or_default();
+                tile_edges.resize(t + variants, 0);
+                for (i, variant_edges) in tile_edges[t..].iter_mut().enumerate() {
+                    *variant_edges = Self::transform_edges(edges, i);
+                }
+            }
 
             for i in 0..variants {
                 action.push(Self::get_map_row(i, t, a, b));
@@ -367,29 +667,40 @@ impl SimpleTiledModel {
                 tilenames.push(format!("{} {}", tile_name, i));
             }
 
-            (tiles, tilesize) = Self::load_tiles_bitmap(domain_name, unique, &tile_name, tiles, variants, t)?;
+            tilesize = Some(Self::load_tiles_bitmap(domain_name, unique, &tile_name, &mut tiles, variants, t, tilesize)?);
         }
 
         t = action.len();
+        for tile_edges in channels.values_mut() {
+            tile_edges.resize(t, 0);
+        }
+        let tilesize = tilesize.ok_or_else(|| WfcError::MissingXml("Tag <tile>".to_string()))?;
 
-        Ok((t, tilesize, weights, tiles, tilenames, action, first_occurrence))
+        Ok(TileData { t, tilesize, weights, tiles, tilenames, action, first_occurrence, counts, channels })
     }
 
-    fn get_propagator(doc: &Document, t: usize, first_occurrence: HashMap<String, usize>, action: Vec<Vec<usize>>) -> Result<Vec<Vec<Vec<usize>>>, Box<dyn std::error::Error>> {
+    fn get_propagator(doc: &Document, t: usize, first_occurrence: HashMap<String, usize>, action: Vec<Vec<usize>>, subset: Option<&Vec<String>>) -> Result<Vec<Vec<Vec<usize>>>, WfcError> {
 
         let mut dense_propagator: Vec<Vec<Vec<bool>>> = vec![vec![vec![false; t]; t]; 4];
 
         let neighbor_tag = doc.descendants()
             .find(|n| n.has_tag_name("neighbors"))
-            .ok_or_else(|| "Tag <neighbors> not found in the document!")?;
+            .ok_or_else(|| WfcError::MissingXml("Tag <neighbors>".to_string()))?;
 
         for neighbor in neighbor_tag.children().filter(|n| n.has_tag_name("neighbor")){
-            let left: Vec<&str> = neighbor.attribute("left").unwrap().split_whitespace().collect();
-            let right: Vec<&str> = neighbor.attribute("right").unwrap().split_whitespace().collect();
+            let left = neighbor.attribute("left")
+                .ok_or_else(|| WfcError::MissingXml("Attribute left of <neighbor>".to_string()))?;
+            let right = neighbor.attribute("right")
+                .ok_or_else(|| WfcError::MissingXml("Attribute right of <neighbor>".to_string()))?;
 
-            let l: usize = action[first_occurrence[left[0]]][if left.len() == 1 {0} else {left[1].parse()?}];
+            let in_subset = |name: &str| subset.is_none_or(|subset| name.split_whitespace().next().is_some_and(|n| subset.iter().any(|s| s == n)));
+            if !in_subset(left) || !in_subset(right) {
+                continue;
+            }
+
+            let l: usize = Self::resolve_tile(left, &first_occurrence, &action)?;
             let d: usize = action[l][1];
-            let r: usize = action[first_occurrence[right[0]]][if right.len() == 1 {0} else {right[1].parse()?}];
+            let r: usize = Self::resolve_tile(right, &first_occurrence, &action)?;
             let u: usize = action[r][1];
 
             dense_propagator[0][r][l] = true;
@@ -403,19 +714,20 @@ impl SimpleTiledModel {
             dense_propagator[1][action[d][2]][action[u][2]] = true;
         }
 
-        for i in 0..t {
-            for j in 0..t{
-                dense_propagator[2][i][j] = dense_propagator[0][j][i];
-                dense_propagator[3][i][j] = dense_propagator[1][j][i];
-            }
-        }
+        // Directions 2 and 3 are the transpose of 0 and 1
+        let transpose = |d: usize| -> Vec<Vec<bool>> {
+            (0..t).map(|i| (0..t).map(|j| dense_propagator[d][j][i]).collect()).collect()
+        };
+        let (right, up) = (transpose(0), transpose(1));
+        dense_propagator[2] = right;
+        dense_propagator[3] = up;
         
         let mut sparse_propagator: Vec<Vec<Vec<usize>>> = vec![vec![Vec::new(); t]; 4];
 
         for d in 0..4{
             for t1 in 0..t{
-                for t2 in 0..t{
-                    if dense_propagator[d][t1][t2]{
+                for (t2, &allowed) in dense_propagator[d][t1].iter().enumerate(){
+                    if allowed{
                         sparse_propagator[d][t1].push(t2);
                     }
                 }
@@ -425,7 +737,69 @@ impl SimpleTiledModel {
         Ok(sparse_propagator)
     }
 
-    const DX: [isize; 4] = [-1, 0, 1, 0];
-    const DY: [isize; 4] = [0, 1, 0, -1];
-    const OPPOSITE: [usize; 4] = [2, 3, 0, 1];
-}
\ No newline at end of file
+    /// Index of the tile referred to as `"<name>"` or `"<name> <variant>"`
+    fn resolve_tile(value: &str, first_occurrence: &HashMap<String, usize>, action: &[Vec<usize>]) -> Result<usize, WfcError> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        let unknown = || WfcError::UnknownTile(value.to_string());
+
+        let first = match parts.first() {
+            Some(name) => *first_occurrence.get(*name).ok_or_else(unknown)?,
+            None => return Err(unknown()),
+        };
+        let variant: usize = match parts.len() {
+            1 => 0,
+            2 => parts[1].parse().map_err(|_| unknown())?,
+            _ => return Err(unknown()),
+        };
+
+        action[first].get(variant).copied().ok_or_else(unknown)
+    }
+
+}
+
+impl Generator for SimpleTiledModel {
+    fn model(&self) -> &Model {
+        &self.model
+    }
+
+    fn model_mut(&mut self) -> &mut Model {
+        &mut self.model
+    }
+
+    fn save(&self, path: &str) -> Result<(), WfcError> {
+        SimpleTiledModel::save(self, path)
+    }
+
+    fn text_output(&self) -> String {
+        SimpleTiledModel::text_output(self)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inactive_cells_are_left_out() {
+        let mut model = SimpleTiledModel::new(&"tilesets/Castle.xml", 8).unwrap();
+        // A ring around a hole of 4x4 cells
+        let active: Vec<bool> = (0..64).map(|i| !(2..6).contains(&(i % 8)) || !(2..6).contains(&(i / 8))).collect();
+        model.model_mut().set_active(Some(active.clone())).unwrap();
+        model.model_mut().set_backtracking(Some(100));
+        assert!(model.pin(3, 3, &model.tile_names()[0].clone()).is_err());
+        model.model_mut().run_with_retries(-1, 7, 10).unwrap();
+
+        let text = model.text_output();
+        let rows: Vec<Vec<&str>> = text.lines().map(|line| line.split(", ").collect()).collect();
+        for (i, &is_active) in active.iter().enumerate() {
+            let (x, y) = (i % 8, i / 8);
+            if is_active {
+                assert!(model.model().observed()[i].is_some(), "cell ({x}, {y})");
+                assert!(!rows[y][x].is_empty(), "cell ({x}, {y})");
+            } else {
+                assert_eq!(model.model().observed()[i], None, "cell ({x}, {y})");
+                assert!((0..model.model().tile_count()).all(|t| model.model().is_allowed(i, t)), "cell ({x}, {y})");
+                assert_eq!(rows[y][x], "", "cell ({x}, {y})");
+            }
+        }
+    }
+}
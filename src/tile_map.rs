@@ -0,0 +1,154 @@
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+use crate::error::WfcError;
+
+/// Number of cells added on each side of a map when extending it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Margins {
+    pub left: usize,
+    pub top: usize,
+    pub right: usize,
+    pub bottom: usize,
+}
+
+/// Grid of tile names produced by a previous generation, loaded back to be pinned into a new one.
+/// `None` marks the cells that weren't observed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileMap {
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) cells: Vec<Option<String>>,
+}
+
+impl TileMap {
+
+    /// `cells` holds `width` x `height` tile names in row-major order
+    pub fn new(width: usize, height: usize, cells: Vec<Option<String>>) -> Result<Self, WfcError> {
+        if cells.len() != width * height {
+            return Err(WfcError::InvalidParameter(format!("A {width}x{height} map needs {} cells, got {}", width * height, cells.len())));
+        }
+        Ok(TileMap { width, height, cells })
+    }
+
+    /// Loads a map saved with [`save`](Self::save): JSON if the file ends in `.json`, the format of
+    /// [`SimpleTiledModel::text_output`](crate::SimpleTiledModel::text_output) otherwise
+    pub fn load<P: AsRef<Path>>(path: &P) -> Result<Self, WfcError> {
+        let content = fs::read_to_string(path)?;
+        if Self::is_json(path) {
+            Self::from_json(&content)
+        } else {
+            Self::from_text(&content)
+        }
+    }
+
+    /// Saves the map at `path`, as JSON if it ends in `.json` and as text otherwise
+    pub fn save<P: AsRef<Path>>(&self, path: &P) -> Result<(), WfcError> {
+        let content = if Self::is_json(path) { self.to_json() } else { self.to_text() };
+        fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// Parses rows of comma separated tile names, where `unobserved` or a blank marks an empty cell
+    pub fn from_text(text: &str) -> Result<Self, WfcError> {
+        let rows: Vec<Vec<Option<String>>> = text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                // Every cell is followed by a comma, the text after the last one isn't a cell
+                let line = line.trim_end();
+                let line = line.strip_suffix(',').unwrap_or(line);
+                line.split(',')
+                    .map(str::trim)
+                    .map(|cell| (!cell.is_empty() && cell != "unobserved").then(|| cell.to_string()))
+                    .collect()
+            })
+            .collect();
+
+        Self::from_rows(rows)
+    }
+
+    /// Parses a JSON array of rows, each an array of tile names or `null` for an empty cell
+    pub fn from_json(json: &str) -> Result<Self, WfcError> {
+        let invalid = || WfcError::InvalidParameter("A JSON map must be an array of rows of tile names or null".to_string());
+
+        let value: Value = serde_json::from_str(json)?;
+        let rows = value.as_array()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|row| {
+                row.as_array()
+                    .ok_or_else(invalid)?
+                    .iter()
+                    .map(|cell| match cell {
+                        Value::String(name) => Ok(Some(name.clone())),
+                        Value::Null => Ok(None),
+                        _ => Err(invalid()),
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::from_rows(rows)
+    }
+
+    /// Same format as [`SimpleTiledModel::text_output`](crate::SimpleTiledModel::text_output)
+    pub fn to_text(&self) -> String {
+        let mut result = String::new();
+
+        for row in self.cells.chunks(self.width) {
+            for cell in row {
+                result.push_str(cell.as_deref().unwrap_or("unobserved"));
+                result.push_str(", ");
+            }
+            result.push('\n');
+        }
+
+        result
+    }
+
+    /// One row per line, so that maps stay readable and diffable
+    pub fn to_json(&self) -> String {
+        let rows: Vec<String> = self.cells.chunks(self.width)
+            .map(|row| Value::from(row.to_vec()).to_string())
+            .collect();
+
+        format!("[\n  {}\n]\n", rows.join(",\n  "))
+    }
+
+    /* Accessors */
+
+    /// Width of the map, in tiles
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height of the map, in tiles
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Tile name of the cell at (`x`, `y`), `None` if it wasn't observed
+    pub fn get(&self, x: usize, y: usize) -> Option<&str> {
+        self.cells[x + y * self.width].as_deref()
+    }
+
+    /* Helper Functions */
+
+    fn from_rows(rows: Vec<Vec<Option<String>>>) -> Result<Self, WfcError> {
+        let width = rows.first().map_or(0, Vec::len);
+        if width == 0 {
+            return Err(WfcError::InvalidParameter("The map is empty".to_string()));
+        }
+        if let Some((y, row)) = rows.iter().enumerate().find(|(_, row)| row.len() != width) {
+            return Err(WfcError::InvalidParameter(format!("Row {} of the map has {} cells, expected {width}", y + 1, row.len())));
+        }
+
+        let height = rows.len();
+        Self::new(width, height, rows.into_iter().flatten().collect())
+    }
+
+    fn is_json<P: AsRef<Path>>(path: &P) -> bool {
+        path.as_ref().extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+    }
+}
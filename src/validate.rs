@@ -0,0 +1,273 @@
+use roxmltree::{Document, Node};
+use std::{collections::HashMap, fmt, fs};
+use std::path::Path;
+
+use crate::{bitmap_utils, count::{self, Count}, error::WfcError, model::Generator, simple_tiled::SimpleTiledModel};
+
+/// How serious a [`Diagnostic`] is: errors make the tileset unusable, warnings point at
+/// things that are probably mistakes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a tileset, with the position in the XML of the element or attribute it refers to
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: u32,
+    pub column: u32,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}:{}: {}: {}", self.line, self.column, severity, self.message)
+    }
+}
+
+const DIRECTIONS: [&str; 4] = ["left", "bottom", "right", "top"];
+
+/// Checks the tileset at `xml_path` without stopping at the first problem: tile images must exist and
+/// have the same square size, neighbors must reference known tiles and variants, symmetries must be
+/// recognized, `min` and `max` counts must be numbers of cells or percentages, channels must list known
+/// sides, regions must have a color and list known tiles, and every tile should have at least one legal neighbor in each direction.
+/// Only a file that can't be read or isn't XML at all is reported as an error.
+pub fn validate_tileset<P: AsRef<Path>>(xml_path: &P) -> Result<Vec<Diagnostic>, WfcError> {
+    let xml_content = fs::read_to_string(xml_path)?;
+    let doc = Document::parse(&xml_content)?;
+    let mut validator = Validator { doc: &doc, diagnostics: Vec::new() };
+
+    let domain_name = xml_path.as_ref()
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let unique = doc.root_element().attribute("unique")
+        .is_some_and(|unique| unique.to_lowercase().parse().unwrap_or(false));
+
+    let tiles = validator.check_tiles(&domain_name, unique);
+    validator.check_neighbors(&tiles);
+    validator.check_subsets(&tiles);
+    validator.check_regions(&tiles);
+
+    // Legal neighbors can only be computed from a tileset without errors
+    if validator.diagnostics.iter().all(|d| d.severity == Severity::Warning) {
+        let model = SimpleTiledModel::with_dimensions(xml_path, 1, 1)?;
+        for (t, name) in model.tile_names().iter().enumerate() {
+            let base_name = name.rsplit_once(' ').map_or(name.as_str(), |(base, _)| base);
+            let Some(&(node, _)) = tiles.get(base_name) else {
+                continue;
+            };
+            for (d, direction) in DIRECTIONS.iter().enumerate() {
+                if model.model().neighbors(t, d).is_empty() {
+                    validator.report(Severity::Warning, node.range().start, format!("tile \"{name}\" has no legal neighbor on its {direction} side"));
+                }
+            }
+        }
+    }
+
+    let mut diagnostics = validator.diagnostics;
+    diagnostics.sort_by_key(|d| (d.line, d.column));
+    Ok(diagnostics)
+}
+
+struct Validator<'a, 'input> {
+    doc: &'a Document<'input>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a, 'input> Validator<'a, 'input> {
+
+    fn report(&mut self, severity: Severity, position: usize, message: String) {
+        let pos = self.doc.text_pos_at(position);
+        self.diagnostics.push(Diagnostic { severity, line: pos.row, column: pos.col, message });
+    }
+
+    /// Position of the attribute `name` of `node`, or of the node itself when the attribute is missing
+    fn position(node: Node, name: &str) -> usize {
+        node.attribute_node(name).map_or(node.range().start, |a| a.position())
+    }
+
+    /// Returns every tile with its node and number of variants
+    fn check_tiles(&mut self, domain_name: &str, unique: bool) -> HashMap<&'a str, (Node<'a, 'input>, usize)> {
+        let mut tiles = HashMap::new();
+        let Some(tiles_tag) = self.doc.descendants().find(|n| n.has_tag_name("tiles")) else {
+            self.report(Severity::Error, self.doc.root_element().range().start, "tag <tiles> not found".to_string());
+            return tiles;
+        };
+
+        let mut tilesize: Option<u32> = None;
+
+        for node in tiles_tag.children().filter(|n| n.has_tag_name("tile")) {
+            let Some(name) = node.attribute("name") else {
+                self.report(Severity::Error, node.range().start, "<tile> without a name".to_string());
+                continue;
+            };
+
+            let symmetry = node.attribute("symmetry").unwrap_or("X");
+            let variants = match SimpleTiledModel::get_cardinality_a_b_on_symmetry(symmetry) {
+                Some((variants, _, _)) => variants,
+                None => {
+                    self.report(Severity::Error, Self::position(node, "symmetry"), format!("tile \"{name}\" has unknown symmetry \"{symmetry}\" (expected X, L, T, I, \\ or F)"));
+                    1
+                }
+            };
+
+            if let Some(weight) = node.attribute("weight") && !weight.parse::<f32>().is_ok_and(|w| w.is_finite() && w > 0.0) {
+                self.report(Severity::Warning, Self::position(node, "weight"), format!("tile \"{name}\" has weight \"{weight}\", which is not a positive number: 1 is used instead"));
+            }
+
+            let mut bounds = [None, None];
+            for (bound, attribute) in bounds.iter_mut().zip(["min", "max"]) {
+                if let Some(value) = node.attribute(attribute) {
+                    match value.parse::<Count>() {
+                        Ok(count) => *bound = Some(count),
+                        Err(_) => self.report(Severity::Error, Self::position(node, attribute), format!("tile \"{name}\" has {attribute} \"{value}\", which is neither a number of cells nor a percentage")),
+                    }
+                }
+            }
+            if count::unsatisfiable(bounds[0], bounds[1]) {
+                self.report(Severity::Error, Self::position(node, "min"), format!("tile \"{name}\" has a min greater than its max"));
+            }
+
+            for channel in node.children().filter(|n| n.has_tag_name("channel")) {
+                if channel.attribute("name").is_none() {
+                    self.report(Severity::Error, channel.range().start, format!("<channel> without a name in tile \"{name}\""));
+                }
+                match channel.attribute("edges") {
+                    Some(edges) if SimpleTiledModel::parse_edges(edges).is_none() => {
+                        self.report(Severity::Error, Self::position(channel, "edges"), format!("tile \"{name}\" has channel edges \"{edges}\" (expected sides among left, bottom, right and top)"));
+                    }
+                    Some(_) => {}
+                    None => self.report(Severity::Error, channel.range().start, format!("<channel> without edges in tile \"{name}\"")),
+                }
+            }
+
+            if tiles.insert(name, (node, variants)).is_some() {
+                self.report(Severity::Error, Self::position(node, "name"), format!("tile \"{name}\" is defined more than once"));
+            }
+
+            let paths: Vec<String> = if unique {
+                (0..variants).map(|i| format!("tilesets/{domain_name}/{name} {i}.png")).collect()
+            } else {
+                vec![format!("tilesets/{domain_name}/{name}.png")]
+            };
+            for path in paths {
+                match bitmap_utils::load_bitmap(&path) {
+                    Ok((_, width, height)) => {
+                        let expected = *tilesize.get_or_insert(width);
+                        if width != height || width != expected {
+                            self.report(Severity::Error, node.range().start, format!("image {path} is {width}x{height}, expected {expected}x{expected}"));
+                        }
+                    }
+                    Err(e) => self.report(Severity::Error, node.range().start, format!("image {path} can't be loaded: {e}")),
+                }
+            }
+        }
+
+        if tiles.is_empty() {
+            self.report(Severity::Error, tiles_tag.range().start, "the tileset has no tiles".to_string());
+        }
+
+        tiles
+    }
+
+    fn check_neighbors(&mut self, tiles: &HashMap<&str, (Node, usize)>) {
+        let Some(neighbors_tag) = self.doc.descendants().find(|n| n.has_tag_name("neighbors")) else {
+            self.report(Severity::Error, self.doc.root_element().range().start, "tag <neighbors> not found".to_string());
+            return;
+        };
+
+        for neighbor in neighbors_tag.children().filter(|n| n.has_tag_name("neighbor")) {
+            for side in ["left", "right"] {
+                let Some(value) = neighbor.attribute(side) else {
+                    self.report(Severity::Error, neighbor.range().start, format!("<neighbor> without a {side} attribute"));
+                    continue;
+                };
+                let position = Self::position(neighbor, side);
+                let parts: Vec<&str> = value.split_whitespace().collect();
+
+                match parts.as_slice() {
+                    [name] | [name, _] if !tiles.contains_key(name) => {
+                        self.report(Severity::Error, position, format!("unknown tile \"{name}\""));
+                    }
+                    [_] => {}
+                    [name, variant] => {
+                        if !variant.parse::<usize>().is_ok_and(|v| v < 8) {
+                            let variants = tiles[name].1;
+                            self.report(Severity::Error, position, format!("invalid variant \"{variant}\" of tile \"{name}\" (it has {variants}, transformations go from 0 to 7)"));
+                        }
+                    }
+                    _ => self.report(Severity::Error, position, format!("\"{value}\" is not in the form \"<tile> [variant]\"")),
+                }
+            }
+        }
+    }
+
+    fn check_subsets(&mut self, tiles: &HashMap<&str, (Node, usize)>) {
+        let Some(subsets_tag) = self.doc.descendants().find(|n| n.has_tag_name("subsets")) else {
+            return;
+        };
+
+        for subset in subsets_tag.children().filter(|n| n.has_tag_name("subset")) {
+            let subset_name = subset.attribute("name").unwrap_or("");
+            if subset_name.is_empty() {
+                self.report(Severity::Error, subset.range().start, "<subset> without a name".to_string());
+            }
+            for tile in subset.children().filter(|n| n.has_tag_name("tile")) {
+                match tile.attribute("name") {
+                    Some(name) if !tiles.contains_key(name) => {
+                        self.report(Severity::Error, Self::position(tile, "name"), format!("subset \"{subset_name}\" lists unknown tile \"{name}\""));
+                    }
+                    Some(_) => {}
+                    None => self.report(Severity::Error, tile.range().start, format!("<tile> without a name in subset \"{subset_name}\"")),
+                }
+            }
+        }
+    }
+
+    fn check_regions(&mut self, tiles: &HashMap<&str, (Node, usize)>) {
+        let Some(regions_tag) = self.doc.descendants().find(|n| n.has_tag_name("regions")) else {
+            return;
+        };
+
+        let mut colors = HashMap::new();
+        for region in regions_tag.children().filter(|n| n.has_tag_name("region")) {
+            let region_name = region.attribute("name").unwrap_or("");
+            if region_name.is_empty() {
+                self.report(Severity::Error, region.range().start, "<region> without a name".to_string());
+            }
+            match region.attribute("color") {
+                Some(value) => match bitmap_utils::parse_color(value) {
+                    Some(color) => {
+                        if let Some(other) = colors.insert(color, region_name) {
+                            self.report(Severity::Error, Self::position(region, "color"), format!("region \"{region_name}\" has the same color as region \"{other}\""));
+                        }
+                    }
+                    None => self.report(Severity::Error, Self::position(region, "color"), format!("region \"{region_name}\" has color \"{value}\" (expected #rrggbb)")),
+                },
+                None => self.report(Severity::Error, region.range().start, format!("<region> \"{region_name}\" without a color")),
+            }
+
+            let mut empty = true;
+            for tile in region.children().filter(|n| n.has_tag_name("tile")) {
+                empty = false;
+                match tile.attribute("name").map(|name| (name, name.split_whitespace().next().unwrap_or_default())) {
+                    Some((name, base)) if !tiles.contains_key(base) => {
+                        self.report(Severity::Error, Self::position(tile, "name"), format!("region \"{region_name}\" lists unknown tile \"{name}\""));
+                    }
+                    Some(_) => {}
+                    None => self.report(Severity::Error, tile.range().start, format!("<tile> without a name in region \"{region_name}\"")),
+                }
+            }
+            if empty {
+                self.report(Severity::Warning, region.range().start, format!("region \"{region_name}\" has no tiles, the cells painted with it can't be generated"));
+            }
+        }
+    }
+}